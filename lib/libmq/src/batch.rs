@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+
+/// How many deliveries make up a batch, and how long to keep gathering once the first arrives
+#[derive(Debug, Clone)]
+pub struct BatchConfiguration {
+    pub max_size: usize,
+    pub max_wait: Duration,
+}
+impl Default for BatchConfiguration {
+    fn default() -> Self {
+        Self {
+            max_size: 64,
+            max_wait: Duration::from_millis(10),
+        }
+    }
+}
+
+pub(crate) async fn next_batch<S, T, E>(
+    stream: &mut S,
+    batch: &BatchConfiguration,
+    deferred: &mut Option<E>,
+) -> Option<Result<Vec<T>, E>>
+where
+    S: Stream<Item = Result<T, E>> + Unpin,
+{
+    // An error that cut the last batch short comes out on its own next time
+    if let Some(e) = deferred.take() {
+        return Some(Err(e));
+    }
+
+    let first = match stream.next().await? {
+        Ok(first) => first,
+        Err(e) => return Some(Err(e)),
+    };
+
+    let mut items = vec![first];
+    let deadline = tokio::time::Instant::now() + batch.max_wait;
+    while items.len() < batch.max_size {
+        match tokio::time::timeout_at(deadline, stream.next()).await {
+            Ok(Some(Ok(item))) => items.push(item),
+            Ok(Some(Err(e))) => {
+                *deferred = Some(e);
+                break;
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+    Some(Ok(items))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use tokio::time::Instant;
+
+    use super::*;
+
+    fn batch(max_size: usize) -> BatchConfiguration {
+        BatchConfiguration {
+            max_size,
+            max_wait: Duration::from_millis(50),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_at_max_size() {
+        let mut stream = futures::stream::iter((1..=10).map(Ok::<_, ()>));
+        let start = Instant::now();
+        let first = next_batch(&mut stream, &batch(4), &mut None).await;
+        assert_eq!(first, Some(Ok(vec![1, 2, 3, 4])));
+        let second = next_batch(&mut stream, &batch(4), &mut None).await;
+        assert_eq!(second, Some(Ok(vec![5, 6, 7, 8])));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_at_max_wait() {
+        let (items, mut stream) = mpsc::unbounded::<Result<u32, ()>>();
+        items.unbounded_send(Ok(1)).unwrap();
+        items.unbounded_send(Ok(2)).unwrap();
+        let start = Instant::now();
+        let partial = next_batch(&mut stream, &batch(4), &mut None).await;
+        assert_eq!(partial, Some(Ok(vec![1, 2])));
+        // The timer rounds up to its next tick
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(50) && waited < Duration::from_millis(60));
+    }
+}
@@ -1,16 +1,176 @@
+use std::time::Duration;
+
 use derive_builder::Builder;
 
+use crate::{
+    batch::BatchConfiguration,
+    chunk::{self, ChunkConfiguration},
+    confirm::BacklogPolicy,
+    connection::ReconnectPolicy,
+    offset::{CommitPolicy, StartPosition},
+    retention::{CreationPolicy, Retention},
+    security::{Credentials, TlsOptions},
+};
+
 #[derive(Debug, Clone, Builder)]
+#[builder(build_fn(validate = "Self::validate"))]
 pub struct ChannelConfiguration {
     #[builder(setter(into))]
     pub host: String,
     #[builder(setter(into))]
     pub stream_name: String,
+    /// Stream clients publish calls to, defaults to `{stream_name}.calls`
+    #[builder(setter(into, strip_option), default)]
+    pub call_stream: Option<String>,
+    /// Stream servers publish replies to, defaults to `{stream_name}.replies`
+    #[builder(setter(into, strip_option), default)]
+    pub reply_stream: Option<String>,
+    /// Stream servers move calls they can't unpack to, rather than failing on them
+    #[builder(setter(into, strip_option), default)]
+    pub dead_letter_stream: Option<String>,
+    /// Content type to publish with, for packers that can pick one at runtime such as
+    /// `Registry`. Defaults to the packer's own.
+    #[builder(setter(into, strip_option), default)]
+    pub content_type: Option<String>,
+    /// Makes the call stream a super stream with this many partitions. Calls are routed by
+    /// `MessageQueuePayload::routing_key`, so calls sharing a key stay in order.
+    #[builder(setter(strip_option), default)]
+    pub call_partitions: Option<usize>,
     #[builder(default = 5552)]
     pub port: u16,
+    /// Tried in order when `host` can't be reached
+    #[builder(default)]
+    pub fallback_hosts: Vec<(String, u16)>,
+    /// Defaults to the broker's guest user
+    #[builder(setter(strip_option), default)]
+    pub credentials: Option<Credentials>,
+    #[builder(setter(into, strip_option), default)]
+    pub virtual_host: Option<String>,
+    #[builder(setter(strip_option), default)]
+    pub tls: Option<TlsOptions>,
+    /// The broker sits behind a load balancer, so connections are retried until they land on
+    /// the node that was asked for
+    #[builder(default)]
+    pub load_balancer_mode: bool,
+    /// Applied to every stream of the channel when it is created
+    #[builder(default)]
+    pub retention: Retention,
+    #[builder(default)]
+    pub creation_policy: CreationPolicy,
+    #[builder(default = "Duration::from_secs(30)")]
+    pub call_timeout: Duration,
+    /// How long a call sent with `MessageQueueClient::send` stays worth answering. Servers drop
+    /// calls past their deadline rather than handing them to the application. `call` always
+    /// gives up with its timeout.
+    #[builder(setter(strip_option), default)]
+    pub call_ttl: Option<Duration>,
+    /// Batch shape used by `recv`
+    #[builder(default)]
+    pub batch: BatchConfiguration,
+    #[builder(default)]
+    pub chunking: ChunkConfiguration,
+    /// Offsets are stored under this name, so a restart resumes where it left off
+    #[builder(setter(into, strip_option), default)]
+    pub consumer_name: Option<String>,
+    #[builder(default)]
+    pub start_position: StartPosition,
+    #[builder(default)]
+    pub commit_policy: CommitPolicy,
+    /// Only one replica sharing the `consumer_name` receives messages at a time, the others take
+    /// over when it goes away
+    #[builder(default)]
+    pub single_active_consumer: bool,
+    /// Publish as a named producer with ids the broker uses to drop retried messages it has
+    /// already stored
+    #[builder(default)]
+    pub deduplicate: bool,
+    /// Name for the deduplicating producer, required with `deduplicate`. The broker tracks ids per
+    /// name, so every replica publishing to the same stream needs a name of its own.
+    #[builder(setter(into, strip_option), default)]
+    pub producer_name: Option<String>,
+    /// Per producer, caps sends still waiting on their confirm
+    #[builder(default)]
+    pub confirm_backlog: BacklogPolicy,
+    #[builder(default)]
+    pub reconnect: ReconnectPolicy,
+}
+impl ChannelConfigurationBuilder {
+    fn validate(&self) -> Result<(), String> {
+        if self.single_active_consumer == Some(true) && !matches!(self.consumer_name, Some(Some(_)))
+        {
+            return Err("single_active_consumer requires a consumer_name".into());
+        }
+        if self.deduplicate == Some(true) && !matches!(self.producer_name, Some(Some(_))) {
+            return Err("deduplicate requires a producer_name".into());
+        }
+        // Replicas share the consumer name of their group, so it can't be any one's producer name
+        if let (Some(Some(producer_name)), Some(Some(consumer_name))) =
+            (&self.producer_name, &self.consumer_name)
+        {
+            if producer_name == consumer_name {
+                return Err("producer_name must differ from the shared consumer_name".into());
+            }
+        }
+        if self.call_partitions == Some(Some(0)) {
+            return Err("call_partitions must be at least 1".into());
+        }
+        if let Some(BacklogPolicy::Block { max: 0 } | BacklogPolicy::Reject { max: 0 }) =
+            &self.confirm_backlog
+        {
+            return Err("confirm_backlog must allow at least 1 message".into());
+        }
+        if let Some(chunking) = &self.chunking {
+            let overhead = chunk::overhead();
+            if chunking.threshold <= overhead {
+                return Err(format!("chunking threshold must be over {overhead} bytes"));
+            }
+            if chunking.max_pending == 0 {
+                return Err("chunking max_pending must be at least 1".into());
+            }
+        }
+        if let Some(Some(tls)) = &self.tls {
+            if tls.ca_certificate_path.is_none() && !tls.accept_invalid_certificates {
+                return Err("tls requires a ca_certificate_path".into());
+            }
+        }
+        Ok(())
+    }
 }
 impl ChannelConfiguration {
+    /// The channel as an `mq://` URI, with the password masked
     pub fn fmt(&self) -> String {
-        format!("mq://{}:{}/{}", self.host, self.port, self.stream_name)
+        self.to_string()
+    }
+
+    /// Every host to connect to, in the order they are tried
+    pub fn endpoints(&self) -> impl Iterator<Item = (&str, u16)> {
+        std::iter::once((self.host.as_str(), self.port)).chain(
+            self.fallback_hosts
+                .iter()
+                .map(|(host, port)| (host.as_str(), *port)),
+        )
+    }
+
+    pub fn call_stream_name(&self) -> String {
+        self.call_stream
+            .clone()
+            .unwrap_or_else(|| format!("{}.calls", self.stream_name))
+    }
+
+    /// The streams calls are actually written to, one per partition of the call stream
+    pub fn call_stream_partitions(&self) -> Vec<String> {
+        let call_stream = self.call_stream_name();
+        match self.call_partitions {
+            Some(partitions) => (0..partitions)
+                .map(|partition| format!("{call_stream}-{partition}"))
+                .collect(),
+            None => vec![call_stream],
+        }
+    }
+
+    pub fn reply_stream_name(&self) -> String {
+        self.reply_stream
+            .clone()
+            .unwrap_or_else(|| format!("{}.replies", self.stream_name))
     }
 }
@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rabbitmq_stream_client::types::{Message, SimpleValue};
+use rabbitmq_stream_protocol::codec::{Decoder, Encoder};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{
+    confirm::ConfirmHandle,
+    pack::{PackOptions, PackerError, PackerResult},
+    transport::{Pack, PublishError, SendOutcome, TransportDelivery, TransportProducer},
+};
+
+pub const CHUNK_ID_PROPERTY: &str = "x-chunk-id";
+pub const CHUNK_INDEX_PROPERTY: &str = "x-chunk-index";
+pub const CHUNK_TOTAL_PROPERTY: &str = "x-chunk-total";
+pub const CHUNK_CHECKSUM_PROPERTY: &str = "x-chunk-checksum";
+pub const CHUNK_CONTENT_TYPE: &str = "application/x-mq-chunk";
+
+/// When messages are split into chunks, and how long a partly received one is kept
+#[derive(Debug, Clone)]
+pub struct ChunkConfiguration {
+    /// Encoded size above which a message is split, also the most each chunk may take on the
+    /// wire. Keep it under the broker's frame size.
+    pub threshold: usize,
+    /// A message still missing chunks this long after its first arrived is given up on
+    pub timeout: Duration,
+    /// Largest message a consumer puts back together, chunks claiming more are rejected
+    pub max_message_size: usize,
+    /// Most messages a consumer puts back together at once
+    pub max_pending: usize,
+}
+impl Default for ChunkConfiguration {
+    fn default() -> Self {
+        Self {
+            threshold: 512 * 1024,
+            timeout: Duration::from_secs(30),
+            max_message_size: 64 * 1024 * 1024,
+            max_pending: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, valuable::Valuable)]
+#[serde(tag = "$type", content = "reason")]
+pub enum ChunkError {
+    #[error("Message {chunk_id} is incomplete, {received} of {total} chunks arrived in time")]
+    #[serde(rename = "dev.thmsn.mq.chunk.incomplete")]
+    Incomplete {
+        chunk_id: String,
+        received: u32,
+        total: u32,
+    },
+    #[error("Message {chunk_id} failed its checksum, expected {expected:08x} got {actual:08x}")]
+    #[serde(rename = "dev.thmsn.mq.chunk.checksum")]
+    Checksum {
+        chunk_id: String,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("Chunk of message {chunk_id} has a bad header: {reason}")]
+    #[serde(rename = "dev.thmsn.mq.chunk.header")]
+    Header { chunk_id: String, reason: String },
+    #[error("Reassembled message {chunk_id} doesn't decode: {reason}")]
+    #[serde(rename = "dev.thmsn.mq.chunk.decode")]
+    Decode { chunk_id: String, reason: String },
+}
+pub type ChunkResult<T> = Result<T, ChunkError>;
+
+#[derive(Debug, Clone)]
+struct Chunk {
+    id: String,
+    index: u32,
+    total: u32,
+    checksum: u32,
+    bytes: Vec<u8>,
+}
+impl Chunk {
+    fn message(self, options: &PackOptions) -> Message {
+        let builder = Message::builder()
+            .body(self.bytes)
+            .properties()
+            .content_type(CHUNK_CONTENT_TYPE)
+            .message_builder()
+            .application_properties()
+            .insert(CHUNK_ID_PROPERTY, SimpleValue::String(self.id))
+            .insert(CHUNK_INDEX_PROPERTY, SimpleValue::Uint(self.index))
+            .insert(CHUNK_TOTAL_PROPERTY, SimpleValue::Uint(self.total))
+            .insert(CHUNK_CHECKSUM_PROPERTY, SimpleValue::Uint(self.checksum))
+            .message_builder();
+        match options.publishing_id {
+            Some(publishing_id) => builder.publising_id(publishing_id),
+            None => builder,
+        }
+        .build()
+    }
+}
+
+pub(crate) fn overhead() -> usize {
+    let probe = 256;
+    let chunk = Chunk {
+        id: Uuid::nil().to_string(),
+        index: u32::MAX,
+        total: u32::MAX,
+        checksum: u32::MAX,
+        bytes: vec![0; probe],
+    };
+    chunk.message(&PackOptions::default()).encoded_size() as usize - probe
+}
+
+fn split(message: &Message, threshold: usize) -> PackerResult<Option<Vec<Chunk>>> {
+    let size = message.encoded_size() as usize;
+    if size <= threshold {
+        return Ok(None);
+    }
+    let slice = threshold.saturating_sub(overhead());
+    if slice == 0 {
+        return Err(PackerError::Serialize(
+            io::Error::other(format!(
+                "chunk threshold {threshold} leaves no room for a body"
+            ))
+            .into(),
+        ));
+    }
+
+    let mut encoded = Vec::with_capacity(size);
+    message
+        .encode(&mut encoded)
+        .map_err(|e| PackerError::Serialize(io::Error::other(format!("{e:?}")).into()))?;
+    let id = Uuid::new_v4().to_string();
+    let checksum = crc32fast::hash(&encoded);
+    let total = encoded.len().div_ceil(slice) as u32;
+    Ok(Some(
+        encoded
+            .chunks(slice)
+            .enumerate()
+            .map(|(index, bytes)| Chunk {
+                id: id.clone(),
+                index: index as u32,
+                total,
+                checksum,
+                bytes: bytes.to_vec(),
+            })
+            .collect(),
+    ))
+}
+
+fn chunked<'a>(pack: Pack<'a>, threshold: usize) -> (Pack<'a>, Arc<Mutex<Vec<Chunk>>>) {
+    // Packing waits for the producer's options, so the rest of the chunks are only known
+    // once the first has gone
+    let rest = Arc::new(Mutex::new(vec![]));
+    let first_rest = rest.clone();
+    let pack: Pack = Box::new(move |options| {
+        let message = pack(options)?;
+        let Some(chunks) = split(&message, threshold)? else {
+            return Ok(message);
+        };
+        let mut chunks = chunks.into_iter();
+        let first = chunks.next().expect("a split message has chunks");
+        tracing::debug!(chunk_id = first.id, total = first.total, "Sending chunked");
+        *first_rest.lock().unwrap_or_else(|e| e.into_inner()) = chunks.collect();
+        Ok(first.message(options))
+    });
+    (pack, rest)
+}
+
+async fn send_rest(
+    producer: &dyn TransportProducer,
+    rest: Arc<Mutex<Vec<Chunk>>>,
+    confirm: bool,
+) -> Result<(), PublishError> {
+    let rest = std::mem::take(&mut *rest.lock().unwrap_or_else(|e| e.into_inner()));
+    for chunk in rest {
+        producer
+            .send(Box::new(move |options| Ok(chunk.message(options))), confirm)
+            .await?;
+    }
+    Ok(())
+}
+
+// Each chunk is a publish of its own, so a deduplicating producer gives each its own id
+pub(crate) async fn send<'a>(
+    producer: &'a dyn TransportProducer,
+    pack: Pack<'a>,
+    confirm: bool,
+    config: &ChunkConfiguration,
+) -> Result<(), PublishError> {
+    let (pack, rest) = chunked(pack, config.threshold);
+    producer.send(pack, confirm).await?;
+    send_rest(producer, rest, confirm).await
+}
+
+pub(crate) async fn send_tracked<'a>(
+    producer: &'a dyn TransportProducer,
+    pack: Pack<'a>,
+    config: &ChunkConfiguration,
+) -> Result<ConfirmHandle, PublishError> {
+    let (pack, rest) = chunked(pack, config.threshold);
+    let mut handles = vec![producer.send_tracked(pack).await?];
+    let rest = std::mem::take(&mut *rest.lock().unwrap_or_else(|e| e.into_inner()));
+    if rest.is_empty() {
+        return Ok(handles.remove(0));
+    }
+    for chunk in rest {
+        handles.push(
+            producer
+                .send_tracked(Box::new(move |options| Ok(chunk.message(options))))
+                .await?,
+        );
+    }
+    Ok(ConfirmHandle::all(handles))
+}
+
+pub(crate) async fn send_batch<'a>(
+    producer: &'a dyn TransportProducer,
+    packs: Vec<Pack<'a>>,
+    confirm: bool,
+    config: &ChunkConfiguration,
+) -> Vec<SendOutcome> {
+    let (packs, rests): (Vec<_>, Vec<_>) = packs
+        .into_iter()
+        .map(|pack| chunked(pack, config.threshold))
+        .unzip();
+    let mut outcomes = producer.send_batch(packs, confirm).await;
+    for (outcome, rest) in outcomes.iter_mut().zip(rests) {
+        if !outcome.is_success() {
+            continue;
+        }
+        if let Err(e) = send_rest(producer, rest, confirm).await {
+            *outcome = e.into();
+        }
+    }
+    outcomes
+}
+
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    size: usize,
+    checksum: u32,
+    started: Instant,
+}
+
+// A consumer that resumes part way through a chunked message never sees its first chunks,
+// the rest are reported as incomplete once they time out
+pub(crate) struct Reassembler {
+    timeout: Duration,
+    max_message_size: usize,
+    max_chunks: u32,
+    max_pending: usize,
+    partials: HashMap<String, Partial>,
+}
+impl Reassembler {
+    pub fn new(config: &ChunkConfiguration) -> Self {
+        let slice = config.threshold.saturating_sub(overhead()).max(1);
+        Self {
+            timeout: config.timeout,
+            max_message_size: config.max_message_size,
+            max_chunks: u32::try_from(config.max_message_size.div_ceil(slice)).unwrap_or(u32::MAX),
+            max_pending: config.max_pending,
+            partials: HashMap::new(),
+        }
+    }
+
+    pub fn accept(
+        &mut self,
+        delivery: TransportDelivery,
+    ) -> ChunkResult<Option<TransportDelivery>> {
+        let Some(id) = string_property(&delivery.message, CHUNK_ID_PROPERTY) else {
+            return Ok(Some(delivery));
+        };
+        let id = id.to_string();
+        let header = |name: &str| {
+            uint_property(&delivery.message, name).ok_or_else(|| ChunkError::Header {
+                chunk_id: id.clone(),
+                reason: format!("missing {name}"),
+            })
+        };
+        let index = header(CHUNK_INDEX_PROPERTY)?;
+        let total = header(CHUNK_TOTAL_PROPERTY)?;
+        let checksum = header(CHUNK_CHECKSUM_PROPERTY)?;
+        if index >= total {
+            return Err(ChunkError::Header {
+                chunk_id: id,
+                reason: format!("chunk {index} of {total}"),
+            });
+        }
+        if total > self.max_chunks {
+            return Err(ChunkError::Header {
+                chunk_id: id,
+                reason: format!("{total} chunks is over the limit of {}", self.max_chunks),
+            });
+        }
+        if !self.partials.contains_key(&id) && self.partials.len() >= self.max_pending {
+            return Err(ChunkError::Header {
+                chunk_id: id,
+                reason: format!("already reassembling {} messages", self.partials.len()),
+            });
+        }
+
+        let partial = self.partials.entry(id.clone()).or_insert_with(|| Partial {
+            chunks: vec![None; total as usize],
+            received: 0,
+            size: 0,
+            checksum,
+            started: Instant::now(),
+        });
+        if partial.chunks.len() != total as usize || partial.checksum != checksum {
+            self.partials.remove(&id);
+            return Err(ChunkError::Header {
+                chunk_id: id,
+                reason: "chunks disagree on the message they belong to".into(),
+            });
+        }
+        let slot = &mut partial.chunks[index as usize];
+        if slot.is_none() {
+            let bytes = delivery.message.data().unwrap_or_default();
+            partial.size += bytes.len();
+            if partial.size > self.max_message_size {
+                self.partials.remove(&id);
+                return Err(ChunkError::Header {
+                    chunk_id: id,
+                    reason: format!("message is over {} bytes", self.max_message_size),
+                });
+            }
+            *slot = Some(bytes.to_vec());
+            partial.received += 1;
+        }
+        if partial.received < total {
+            return Ok(None);
+        }
+
+        let partial = self.partials.remove(&id).expect("just looked up");
+        let encoded: Vec<u8> = partial.chunks.into_iter().flatten().flatten().collect();
+        let actual = crc32fast::hash(&encoded);
+        if actual != partial.checksum {
+            return Err(ChunkError::Checksum {
+                chunk_id: id,
+                expected: partial.checksum,
+                actual,
+            });
+        }
+        let (_, message) = Message::decode(&encoded).map_err(|e| ChunkError::Decode {
+            chunk_id: id.clone(),
+            reason: format!("{e:?}"),
+        })?;
+        tracing::trace!(chunk_id = id, total, "Reassembled chunked message");
+        Ok(Some(TransportDelivery {
+            offset: delivery.offset,
+            message,
+        }))
+    }
+
+    pub fn expire(&mut self) -> Vec<ChunkError> {
+        let timeout = self.timeout;
+        let mut expired = vec![];
+        self.partials.retain(|id, partial| {
+            if partial.started.elapsed() < timeout {
+                return true;
+            }
+            expired.push(ChunkError::Incomplete {
+                chunk_id: id.clone(),
+                received: partial.received,
+                total: partial.chunks.len() as u32,
+            });
+            false
+        });
+        expired
+    }
+
+    pub async fn next_expiry(&self) {
+        match self.partials.values().map(|partial| partial.started).min() {
+            Some(started) => tokio::time::sleep_until(started + self.timeout).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+fn string_property<'a>(message: &'a Message, name: &str) -> Option<&'a str> {
+    match message.application_properties()?.get(name)? {
+        SimpleValue::String(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn uint_property(message: &Message, name: &str) -> Option<u32> {
+    match message.application_properties()?.get(name)? {
+        SimpleValue::Uint(value) => Some(*value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(size: usize) -> Message {
+        Message::builder().body(vec![7; size]).build()
+    }
+
+    fn deliveries(chunks: Vec<Chunk>) -> Vec<TransportDelivery> {
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(offset, chunk)| TransportDelivery {
+                offset: offset as u64,
+                message: chunk.message(&PackOptions::default()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_messages_are_not_split() {
+        assert!(split(&message(100), 1024).unwrap().is_none());
+    }
+
+    #[test]
+    fn chunks_stay_under_the_threshold() {
+        let chunks = split(&message(10_000), 1024).unwrap().unwrap();
+        assert!(chunks.len() > 10);
+        for chunk in chunks {
+            let size = chunk.message(&PackOptions::default()).encoded_size() as usize;
+            assert!(size <= 1024, "chunk of {size} bytes");
+        }
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let mut reassembler = Reassembler::new(&ChunkConfiguration::default());
+        let mut deliveries = deliveries(split(&message(5_000), 512).unwrap().unwrap());
+        deliveries.reverse();
+        let last = deliveries.pop().unwrap();
+        for delivery in deliveries {
+            assert!(reassembler.accept(delivery).unwrap().is_none());
+        }
+        let whole = reassembler.accept(last).unwrap().unwrap();
+        assert_eq!(whole.message.data(), Some(&vec![7; 5_000][..]));
+    }
+
+    #[test]
+    fn rejects_a_corrupted_chunk() {
+        let mut chunks = split(&message(5_000), 512).unwrap().unwrap();
+        chunks[1].bytes[0] ^= 0xff;
+        let mut reassembler = Reassembler::new(&ChunkConfiguration::default());
+        let outcomes: Vec<_> = deliveries(chunks)
+            .into_iter()
+            .map(|delivery| reassembler.accept(delivery))
+            .collect();
+        assert!(matches!(
+            outcomes.last(),
+            Some(Err(ChunkError::Checksum { .. }))
+        ));
+    }
+
+    #[test]
+    fn expires_incomplete_messages() {
+        let mut reassembler = Reassembler::new(&ChunkConfiguration {
+            threshold: 512,
+            timeout: Duration::ZERO,
+            ..Default::default()
+        });
+        let first = deliveries(split(&message(5_000), 512).unwrap().unwrap()).remove(0);
+        assert!(reassembler.accept(first).unwrap().is_none());
+        assert!(matches!(
+            reassembler.expire().as_slice(),
+            [ChunkError::Incomplete { received: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let mut reassembler = Reassembler::new(&ChunkConfiguration::default());
+        let mut chunk = split(&message(5_000), 512).unwrap().unwrap().remove(0);
+        chunk.total = u32::MAX;
+        let outcome = reassembler.accept(deliveries(vec![chunk]).remove(0));
+        assert!(matches!(outcome, Err(ChunkError::Header { .. })));
+        assert!(reassembler.partials.is_empty());
+
+        let mut reassembler = Reassembler::new(&ChunkConfiguration {
+            threshold: 512,
+            max_message_size: 1_000,
+            ..Default::default()
+        });
+        let outcomes: Vec<_> = deliveries(split(&message(5_000), 512).unwrap().unwrap())
+            .into_iter()
+            .map(|delivery| reassembler.accept(delivery))
+            .collect();
+        assert!(outcomes
+            .iter()
+            .any(|outcome| matches!(outcome, Err(ChunkError::Header { .. }))));
+    }
+
+    #[test]
+    fn limits_messages_in_flight() {
+        let mut reassembler = Reassembler::new(&ChunkConfiguration {
+            max_pending: 1,
+            ..Default::default()
+        });
+        let first = || deliveries(split(&message(5_000), 512).unwrap().unwrap()).remove(0);
+        assert!(reassembler.accept(first()).unwrap().is_none());
+        assert!(matches!(
+            reassembler.accept(first()),
+            Err(ChunkError::Header { .. })
+        ));
+    }
+}
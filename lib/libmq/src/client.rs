@@ -1,23 +1,42 @@
-use std::{marker::PhantomData, time::Duration};
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use liberror::AnyError;
 use serde::{Deserialize, Serialize};
 use tap::TapFallible;
-
-use rabbitmq_stream_client::{
-    error::StreamCreateError,
-    types::{ByteCapacity, ResponseCode},
-    Consumer, Environment, NoDedup, Producer,
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
 };
+use uuid::Uuid;
+
 use thiserror::Error;
 
 use crate::{
+    batch::{next_batch, BatchConfiguration},
     channel::ChannelConfiguration,
+    chunk::{self, ChunkConfiguration, ChunkError, Reassembler},
+    confirm::ConfirmHandle,
+    connection::ConnectionStatus,
+    delivery::Delivery,
     message::{ManagerMessage, ManagerMessagePayload},
     meta::ManagerMeta,
-    pack::{Packer, PackerError},
+    offset::tick,
+    pack::{PackOptions, Packer, PackerError},
+    partition::{route, PartitionEvent, PartitionedConsumer},
     payload::MessageQueuePayload,
+    retention::StreamSetupError,
+    transport::{
+        Pack, PublishError, RabbitMqTransport, SendOutcome, Transport, TransportDelivery,
+        TransportProducer,
+    },
 };
 
 #[derive(Debug, Clone, Error, Serialize, Deserialize, valuable::Valuable)]
@@ -41,17 +60,54 @@ pub enum MessageQueueClientError {
     #[error("Failed to process message: {0}")]
     #[serde(rename = "dev.thmsn.mq.client.packer")]
     Packer(#[from] PackerError),
+    #[error("Failed to reassemble message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.client.chunk")]
+    Chunk(#[from] ChunkError),
+    #[error("Failed to set up stream: {0}")]
+    #[serde(rename = "dev.thmsn.mq.client.stream")]
+    Stream(#[from] StreamSetupError),
+    #[error("No reply to call {request_id} within {timeout_ms}ms")]
+    #[serde(rename = "dev.thmsn.mq.client.call_timeout")]
+    CallTimeout { request_id: String, timeout_ms: u64 },
+    #[error("Call {request_id} was abandoned, the response reader has stopped")]
+    #[serde(rename = "dev.thmsn.mq.client.call_abandoned")]
+    CallAbandoned { request_id: String },
+    #[error("The consumer has closed")]
+    #[serde(rename = "dev.thmsn.mq.client.closed")]
+    Closed,
 }
 pub type MessageQueueClientResult<T> = Result<T, MessageQueueClientError>;
 
+impl From<PublishError> for MessageQueueClientError {
+    fn from(value: PublishError) -> Self {
+        match value {
+            PublishError::Pack(e) => Self::Packer(e),
+            PublishError::Publish(e) => Self::Send(e),
+        }
+    }
+}
+
+const UNSOLICITED_BUFFER_SIZE: usize = 1024;
+
+type PendingCalls<TResponse> = Arc<Mutex<HashMap<Uuid, oneshot::Sender<Delivery<TResponse>>>>>;
+
 pub struct MessageQueueClient<
     TCall: MessageQueuePayload,
     TResponse: MessageQueuePayload,
     TPacker: Packer,
 > {
     id: String,
-    producer: Producer<NoDedup>,
-    consumer: Consumer,
+    transport: Arc<dyn Transport>,
+    producers: Vec<Box<dyn TransportProducer>>,
+    pending: PendingCalls<TResponse>,
+    unsolicited: mpsc::Receiver<MessageQueueClientResult<Delivery<TResponse>>>,
+    deferred: Option<MessageQueueClientError>,
+    reader: JoinHandle<()>,
+    call_timeout: Duration,
+    call_ttl: Option<Duration>,
+    batch: BatchConfiguration,
+    content_type: Option<String>,
+    chunking: ChunkConfiguration,
     _phantom_call: PhantomData<TCall>,
     _phantom_response: PhantomData<TResponse>,
     _phantom_packer: PhantomData<TPacker>,
@@ -64,125 +120,483 @@ impl<TCall: MessageQueuePayload, TResponse: MessageQueuePayload, TPacker: Packer
         client_name: String,
         mq_config: &ChannelConfiguration,
     ) -> MessageQueueClientResult<Self> {
-        let environment = Environment::builder()
-            .host(&mq_config.host)
-            .port(mq_config.port)
-            .build()
+        let transport = RabbitMqTransport::connect(mq_config)
             .await
             .map_err(|e| MessageQueueClientError::CreateEnvironment(e.to_string()))?;
 
         tracing::info!("Environment created");
 
-        // Ensure the stream exists
-        let created = environment
-            .stream_creator()
-            .max_length(ByteCapacity::GB(1))
-            .create(&mq_config.stream_name)
-            .await;
+        Self::with_transport(client_name, mq_config, transport).await
+    }
 
-        match created.as_ref() {
-            Err(StreamCreateError::Create { status, .. }) => {
-                // Stream exists, ignore
-                match status {
-                    ResponseCode::StreamAlreadyExists => {}
-                    // general create error
-                    e => {
-                        tracing::error!("Failed to create stream (1): {e:?}");
-                        return Err(MessageQueueClientError::CreateEnvironment(format!(
-                            "{:?}",
-                            status
-                        )));
-                    }
-                }
-            }
-            // No data
-            Ok(()) => {}
-            // general error
-            Err(e) => {
-                tracing::error!(error = e.to_string(), "Failed to create stream (2): {e}");
-                return Err(MessageQueueClientError::CreateEnvironment(format!(
-                    "{:?}",
-                    created
-                )));
+    /// Run over `transport` instead of connecting to RabbitMQ
+    #[tracing::instrument(name = "mq.client.with_transport", skip(transport))]
+    pub async fn with_transport(
+        client_name: String,
+        mq_config: &ChannelConfiguration,
+        transport: impl Transport,
+    ) -> MessageQueueClientResult<Self> {
+        if let Some(content_type) = &mq_config.content_type {
+            if !TPacker::supports(content_type) {
+                return Err(PackerError::UnsupportedContentType(content_type.clone()).into());
             }
         }
+        let transport: Arc<dyn Transport> = Arc::new(transport);
 
-        let producer = environment
-            .producer()
-            .build(&mq_config.stream_name)
-            .await
-            .tap_err(|e| tracing::error!("{e:?}"))
-            .map_err(|e| MessageQueueClientError::CreateProducer(e.into()))?;
-        tracing::info!("Producer created");
+        // Ensure both halves of the channel exist
+        let call_stream = mq_config.call_stream_name();
+        let reply_stream = mq_config.reply_stream_name();
+        transport
+            .ensure_stream(mq_config, &call_stream, mq_config.call_partitions)
+            .await?;
+        transport
+            .ensure_stream(mq_config, &reply_stream, None)
+            .await?;
 
-        let consumer = environment
-            .consumer()
-            .build(&mq_config.stream_name)
-            .await
-            .map_err(|e| MessageQueueClientError::CreateConsumer(e.into()))?;
+        let mut producers = vec![];
+        for partition in mq_config.call_stream_partitions() {
+            let producer = transport
+                .producer(mq_config, &partition)
+                .await
+                .tap_err(|e| tracing::error!("{e:?}"))
+                .map_err(MessageQueueClientError::CreateProducer)?;
+            producers.push(producer);
+        }
+        tracing::info!(partitions = producers.len(), "Producer created");
+
+        // Every replica reads the whole reply stream for its own replies, so none of them can
+        // share a consumer name or an active slot
+        let mut reply_config = mq_config.clone();
+        reply_config.consumer_name = None;
+        reply_config.single_active_consumer = false;
+        let consumer =
+            PartitionedConsumer::build(&transport, &reply_config, vec![reply_stream], None)
+                .await
+                .map_err(MessageQueueClientError::CreateConsumer)?;
         tracing::info!("Consumer created");
 
+        let pending = PendingCalls::default();
+        let (unsolicited_tx, unsolicited) = mpsc::channel(UNSOLICITED_BUFFER_SIZE);
+        let reader = tokio::spawn(Self::read_responses(
+            consumer,
+            Reassembler::new(&mq_config.chunking),
+            pending.clone(),
+            unsolicited_tx,
+        ));
+
         Ok(Self {
             id: client_name,
-            producer,
-            consumer,
+            transport,
+            producers,
+            pending,
+            unsolicited,
+            deferred: None,
+            reader,
+            call_timeout: mq_config.call_timeout,
+            call_ttl: mq_config.call_ttl,
+            batch: mq_config.batch.clone(),
+            content_type: mq_config.content_type.clone(),
+            chunking: mq_config.chunking.clone(),
             _phantom_call: Default::default(),
             _phantom_response: Default::default(),
             _phantom_packer: Default::default(),
         })
     }
 
-    fn pack_call(&self, call: TCall) -> ManagerMessage<TCall, TResponse> {
-        ManagerMessage::new_call(ManagerMeta::new(&self.id), call)
+    pub fn status(&self) -> ConnectionStatus {
+        self.transport.status()
+    }
+
+    pub fn status_changes(&self) -> watch::Receiver<ConnectionStatus> {
+        self.transport.status_changes()
+    }
+
+    fn producer_for(&self, call: &TCall) -> &dyn TransportProducer {
+        self.producers[route(&call.routing_key(), self.producers.len())].as_ref()
+    }
+
+    fn packing(&self, message: ManagerMessage<TCall, TResponse>) -> Pack<'static> {
+        let content_type = self.content_type.clone();
+        Box::new(move |options| {
+            message.pack::<TPacker>(&PackOptions {
+                content_type,
+                ..options.clone()
+            })
+        })
+    }
+
+    async fn publish(
+        &self,
+        producer: &dyn TransportProducer,
+        message: ManagerMessage<TCall, TResponse>,
+        confirm: bool,
+    ) -> Result<(), PublishError> {
+        chunk::send(producer, self.packing(message), confirm, &self.chunking).await
+    }
+
+    fn pack_call(&self, call: TCall, ttl: Option<Duration>) -> ManagerMessage<TCall, TResponse> {
+        let meta = ManagerMeta::new(&self.id);
+        let meta = match ttl {
+            Some(ttl) => meta.with_ttl(ttl),
+            None => meta,
+        };
+        ManagerMessage::new_call(meta, call)
     }
 
     #[tracing::instrument(name = "mq.client.send", skip(self))]
     pub async fn send(&self, call: TCall) -> MessageQueueClientResult<()> {
-        let message = TPacker::pack(self.pack_call(call))?;
-        self.producer
-            .send(message, |_| async {})
-            .await
-            .map_err(|e| MessageQueueClientError::Send(e.into()))?;
+        let producer = self.producer_for(&call);
+        let message = self.pack_call(call, self.call_ttl);
+        self.publish(producer, message, false).await?;
         Ok(())
     }
 
     #[tracing::instrument(name = "mq.client.send_with_confirm", skip(self))]
     pub async fn send_with_confirm(&self, call: TCall) -> MessageQueueClientResult<()> {
-        let message = TPacker::pack(self.pack_call(call))?;
-        self.producer
-            .send_with_confirm(message)
-            .await
-            .map_err(|e| MessageQueueClientError::Send(e.into()))?;
+        let producer = self.producer_for(&call);
+        let message = self.pack_call(call, self.call_ttl);
+        self.publish(producer, message, true).await?;
         Ok(())
     }
 
-    pub async fn recv(&mut self) -> MessageQueueClientResult<Vec<TResponse>> {
-        let mut messages = vec![];
-        loop {
-            let result =
-                tokio::time::timeout(Duration::from_micros(10), self.consumer.next()).await;
-            let delivery = match result {
-                Ok(Some(delivery)) => {
-                    delivery.map_err(|e| MessageQueueClientError::Receive(e.into()))?
+    /// Returns once published, the handle resolves when the broker confirms or rejects the call
+    #[tracing::instrument(name = "mq.client.send_tracked", skip(self))]
+    pub async fn send_tracked(&self, call: TCall) -> MessageQueueClientResult<ConfirmHandle> {
+        let producer = self.producer_for(&call);
+        let message = self.pack_call(call, self.call_ttl);
+        Ok(chunk::send_tracked(producer, self.packing(message), &self.chunking).await?)
+    }
+
+    /// Outcomes come back in the order of `calls`, so only the failures need sending again
+    #[tracing::instrument(name = "mq.client.send_batch", skip_all, fields(count = calls.len()))]
+    pub async fn send_batch(&self, calls: Vec<TCall>) -> Vec<SendOutcome> {
+        self.publish_batch(calls, false).await
+    }
+
+    #[tracing::instrument(name = "mq.client.send_batch_with_confirm", skip_all, fields(count = calls.len()))]
+    pub async fn send_batch_with_confirm(&self, calls: Vec<TCall>) -> Vec<SendOutcome> {
+        self.publish_batch(calls, true).await
+    }
+
+    async fn publish_batch(&self, calls: Vec<TCall>, confirm: bool) -> Vec<SendOutcome> {
+        let mut partitions: Vec<Vec<(usize, Pack)>> =
+            self.producers.iter().map(|_| vec![]).collect();
+        for (index, call) in calls.into_iter().enumerate() {
+            let partition = route(&call.routing_key(), self.producers.len());
+            let message = self.pack_call(call, self.call_ttl);
+            partitions[partition].push((index, self.packing(message)));
+        }
+
+        let batches = partitions
+            .into_iter()
+            .zip(&self.producers)
+            .filter(|(batch, _)| !batch.is_empty())
+            .map(|(batch, producer)| async move {
+                let (indices, packs): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+                let outcomes =
+                    chunk::send_batch(producer.as_ref(), packs, confirm, &self.chunking).await;
+                indices.into_iter().zip(outcomes)
+            });
+        let mut outcomes: Vec<_> = futures::future::join_all(batches)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        outcomes.sort_by_key(|(index, _)| *index);
+
+        let failed = outcomes
+            .iter()
+            .filter(|(_, outcome)| !outcome.is_success())
+            .count();
+        if failed > 0 {
+            tracing::warn!(failed, "Some calls of the batch didn't make it");
+        }
+        outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+    }
+
+    /// Waits for the reply for up to the channel's `call_timeout`
+    pub async fn call(&self, call: TCall) -> MessageQueueClientResult<TResponse> {
+        self.call_with_timeout(call, self.call_timeout).await
+    }
+
+    #[tracing::instrument(name = "mq.client.call", skip(self))]
+    pub async fn call_with_timeout(
+        &self,
+        call: TCall,
+        timeout: Duration,
+    ) -> MessageQueueClientResult<TResponse> {
+        let producer = self.producer_for(&call);
+        // Nobody is waiting for the reply once the timeout is up
+        let message = self.pack_call(call, Some(timeout));
+        let request_id = message.meta.request_id;
+
+        // Register before publishing so a fast reply can't beat us to the map
+        let (tx, rx) = oneshot::channel();
+        self.lock_pending().insert(request_id, tx);
+
+        if let Err(e) = self.publish(producer, message, false).await {
+            self.lock_pending().remove(&request_id);
+            return Err(e.into());
+        }
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(delivery)) => {
+                tracing::trace!(
+                    "recv reply {} to {}: {}",
+                    delivery.offset,
+                    request_id,
+                    delivery.payload.discriminant()
+                );
+                Ok(delivery.payload)
+            }
+            Ok(Err(_closed)) => Err(MessageQueueClientError::CallAbandoned {
+                request_id: request_id.to_string(),
+            }),
+            Err(_elapsed) => {
+                self.lock_pending().remove(&request_id);
+                Err(MessageQueueClientError::CallTimeout {
+                    request_id: request_id.to_string(),
+                    timeout_ms: timeout.as_millis() as u64,
+                })
+            }
+        }
+    }
+
+    /// Responses that didn't answer a pending `call`
+    pub async fn recv(&mut self) -> MessageQueueClientResult<Vec<Delivery<TResponse>>> {
+        let batch = self.batch.clone();
+        self.recv_batch(&batch).await
+    }
+
+    pub async fn recv_batch(
+        &mut self,
+        batch: &BatchConfiguration,
+    ) -> MessageQueueClientResult<Vec<Delivery<TResponse>>> {
+        let unsolicited = &mut self.unsolicited;
+        let mut responses = futures::stream::poll_fn(|cx| unsolicited.poll_recv(cx));
+        next_batch(&mut responses, batch, &mut self.deferred)
+            .await
+            .unwrap_or(Err(MessageQueueClientError::Closed))
+    }
+
+    /// `recv_batch` as a stream, ending when the consumer closes
+    pub fn batched(
+        &mut self,
+        batch: BatchConfiguration,
+    ) -> impl Stream<Item = MessageQueueClientResult<Vec<Delivery<TResponse>>>> + '_ {
+        futures::stream::unfold(self, move |client| {
+            let batch = batch.clone();
+            async move {
+                match client.recv_batch(&batch).await {
+                    Err(MessageQueueClientError::Closed) => None,
+                    responses => Some((responses, client)),
                 }
-                Ok(None) => break,
-                Err(_elapsed) => break,
-            };
+            }
+        })
+    }
+
+    fn lock_pending(
+        &self,
+    ) -> std::sync::MutexGuard<'_, HashMap<Uuid, oneshot::Sender<Delivery<TResponse>>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    async fn read_responses(
+        mut consumer: PartitionedConsumer,
+        mut chunks: Reassembler,
+        pending: PendingCalls<TResponse>,
+        unsolicited: mpsc::Sender<MessageQueueClientResult<Delivery<TResponse>>>,
+    ) {
+        let mut interval = consumer.interval();
+        loop {
+            tokio::select! {
+                delivery = consumer.next() => {
+                    let delivery = match delivery {
+                        Some((_, PartitionEvent::Delivery(Ok(delivery)))) => delivery,
+                        Some((_, PartitionEvent::Delivery(Err(e)))) => {
+                            let _ = unsolicited.try_send(Err(MessageQueueClientError::Receive(e)));
+                            continue;
+                        }
+                        Some((partition, PartitionEvent::Closed)) => {
+                            match consumer.recover(partition).await {
+                                Ok(()) => continue,
+                                Err(e) => {
+                                    let _ = unsolicited.try_send(Err(MessageQueueClientError::Receive(e)));
+                                    break;
+                                }
+                            }
+                        }
+                        None => break,
+                    };
+                    let delivery = match chunks.accept(delivery) {
+                        Ok(Some(delivery)) => delivery,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            let _ = unsolicited.try_send(Err(e.into()));
+                            continue;
+                        }
+                    };
 
-            let message = delivery.message();
-            let payload: ManagerMessage<TCall, TResponse> = TPacker::unpack(message)?;
-            match payload.payload {
-                ManagerMessagePayload::Call(manager_call) => {
-                    let disc = manager_call.discriminant();
-                    tracing::trace!("ignore recv'd call {}: {}", delivery.offset(), disc);
+                    Self::dispatch(&delivery, &pending, &unsolicited);
+                    if let Err(e) = consumer.processed(0, delivery.offset, 1).await {
+                        tracing::warn!("Failed to store consumer offset: {e}");
+                    }
+                }
+                _ = tick(&mut interval) => {
+                    if let Err(e) = consumer.store().await {
+                        tracing::warn!("Failed to store consumer offset: {e}");
+                    }
                 }
-                ManagerMessagePayload::Response(manager_response) => {
-                    let disc = manager_response.discriminant();
-                    tracing::trace!("recv response {}: {}", delivery.offset(), disc);
-                    messages.push(manager_response);
+                _ = chunks.next_expiry() => {
+                    for e in chunks.expire() {
+                        tracing::warn!("{e}");
+                        let _ = unsolicited.try_send(Err(e.into()));
+                    }
                 }
             }
         }
-        Ok(messages)
+        tracing::warn!("Response consumer closed");
+
+        // Wake every outstanding call rather than letting them run out their timeouts
+        pending.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    fn dispatch(
+        delivery: &TransportDelivery,
+        pending: &PendingCalls<TResponse>,
+        unsolicited: &mpsc::Sender<MessageQueueClientResult<Delivery<TResponse>>>,
+    ) {
+        let payload: ManagerMessage<TCall, TResponse> =
+            match ManagerMessage::unpack::<TPacker>(&delivery.message) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    let _ = unsolicited.try_send(Err(e.into()));
+                    return;
+                }
+            };
+        let response = match payload.payload {
+            ManagerMessagePayload::Call(manager_call) => {
+                let disc = manager_call.discriminant();
+                tracing::trace!("ignore recv'd call {}: {}", delivery.offset, disc);
+                return;
+            }
+            ManagerMessagePayload::Response(manager_response) => {
+                Delivery::new(delivery.offset, payload.meta, manager_response)
+            }
+        };
+
+        let waiter = response.meta.parent_id.and_then(|parent_id| {
+            pending
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&parent_id)
+        });
+        let response = match waiter {
+            // The caller may have timed out in the meantime, in which case nobody is listening
+            Some(waiter) => match waiter.send(response) {
+                Ok(()) => return,
+                Err(response) => response,
+            },
+            None => response,
+        };
+
+        let disc = response.payload.discriminant();
+        tracing::trace!("recv response {}: {}", response.offset, disc);
+        if unsolicited.try_send(Ok(response)).is_err() {
+            tracing::trace!("drop unsolicited response {}: {}", delivery.offset, disc);
+        }
+    }
+}
+
+/// Yields what `recv` would, a response at a time
+impl<TCall: MessageQueuePayload, TResponse: MessageQueuePayload, TPacker: Packer> Stream
+    for MessageQueueClient<TCall, TResponse, TPacker>
+{
+    type Item = MessageQueueClientResult<Delivery<TResponse>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(e) = self.deferred.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        self.unsolicited.poll_recv(cx)
+    }
+}
+
+impl<TCall: MessageQueuePayload, TResponse: MessageQueuePayload, TPacker: Packer> Drop
+    for MessageQueueClient<TCall, TResponse, TPacker>
+{
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::ChannelConfigurationBuilder, pack::JsonPacker, server::MessageQueueServer,
+        transport::InMemoryTransport,
+    };
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Echo(String);
+    impl MessageQueuePayload for Echo {
+        type Discriminant = &'static str;
+
+        fn discriminant(&self) -> Self::Discriminant {
+            "echo"
+        }
+    }
+
+    type EchoClient = MessageQueueClient<Echo, Echo, JsonPacker>;
+    type EchoServer = MessageQueueServer<Echo, Echo, JsonPacker>;
+
+    fn channel() -> ChannelConfiguration {
+        ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("echo")
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn calls_are_answered() {
+        let transport = InMemoryTransport::new();
+        let mut server = EchoServer::with_transport("server".into(), &channel(), transport.clone())
+            .await
+            .unwrap();
+        let client = EchoClient::with_transport("client".into(), &channel(), transport)
+            .await
+            .unwrap();
+
+        let serving = tokio::spawn(async move {
+            for call in server.recv().await.unwrap() {
+                let response = Echo(call.payload.0.to_uppercase());
+                server.reply(&call.meta, response).await.unwrap();
+            }
+        });
+        assert_eq!(
+            client.call(Echo("hello".into())).await.unwrap(),
+            Echo("HELLO".into())
+        );
+        serving.await.unwrap();
+        assert!(client.lock_pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unanswered_calls_time_out() {
+        let client =
+            EchoClient::with_transport("client".into(), &channel(), InMemoryTransport::new())
+                .await
+                .unwrap();
+
+        let result = client
+            .call_with_timeout(Echo("hello".into()), Duration::from_millis(20))
+            .await;
+        assert!(matches!(
+            result,
+            Err(MessageQueueClientError::CallTimeout { timeout_ms: 20, .. })
+        ));
+        assert!(client.lock_pending().is_empty());
     }
 }
@@ -0,0 +1,286 @@
+use std::{borrow::Cow, io, marker::PhantomData};
+
+use rabbitmq_stream_client::types::{Message, SimpleValue};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::pack::{Encoded, Packer, PackerError, PackerResult, PackerValidateError};
+
+/// A compression algorithm, named on the wire by its content encoding
+pub trait Codec: std::fmt::Debug + Send + Sync + Unpin + 'static {
+    const CONTENT_ENCODING: &'static str;
+
+    fn compress(bytes: &[u8]) -> io::Result<Vec<u8>>;
+    /// Stops reading once past `limit` bytes, so a small body can't inflate without bound
+    fn decompress(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>>;
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+fn read_limited(reader: impl io::Read, limit: usize) -> io::Result<Vec<u8>> {
+    let mut decompressed = vec![];
+    io::Read::read_to_end(
+        &mut io::Read::take(reader, limit as u64 + 1),
+        &mut decompressed,
+    )?;
+    Ok(decompressed)
+}
+
+#[cfg(feature = "zstd")]
+#[derive(Debug)]
+pub struct Zstd;
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    const CONTENT_ENCODING: &'static str = "zstd";
+
+    fn compress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    fn decompress(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        read_limited(zstd::Decoder::new(bytes)?, limit)
+    }
+}
+
+/// LZ4 frames, rather than bare blocks, so other LZ4 implementations can read them
+#[cfg(feature = "lz4")]
+#[derive(Debug)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    const CONTENT_ENCODING: &'static str = "lz4";
+
+    fn compress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = lz4_flex::frame::FrameEncoder::new(vec![]);
+        io::Write::write_all(&mut encoder, bytes)?;
+        encoder.finish().map_err(io::Error::other)
+    }
+
+    fn decompress(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        read_limited(lz4_flex::frame::FrameDecoder::new(bytes), limit)
+    }
+}
+
+#[cfg(feature = "gzip")]
+#[derive(Debug)]
+pub struct Gzip;
+
+#[cfg(feature = "gzip")]
+impl Codec for Gzip {
+    const CONTENT_ENCODING: &'static str = "gzip";
+
+    fn compress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        io::Write::write_all(&mut encoder, bytes)?;
+        encoder.finish()
+    }
+
+    fn decompress(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        read_limited(flate2::read::GzDecoder::new(bytes), limit)
+    }
+}
+
+/// Undo the content encoding of a message body, whichever codec wrote it
+pub(crate) fn decompress(
+    content_encoding: &str,
+    bytes: &[u8],
+    limit: usize,
+) -> PackerResult<Vec<u8>> {
+    let decompress = decompressor(content_encoding).ok_or_else(|| {
+        PackerValidateError::IncorrectPropertyValue {
+            property_name: "content_encoding".into(),
+            expected_value: SUPPORTED_ENCODINGS.join(", "),
+            actual_value: content_encoding.to_string(),
+        }
+    })?;
+    let decompressed = decompress(bytes, limit).map_err(|e| PackerError::Decompress(e.into()))?;
+    if decompressed.len() > limit {
+        return Err(PackerError::TooLarge { limit });
+    }
+    Ok(decompressed)
+}
+
+type Decompress = fn(&[u8], usize) -> io::Result<Vec<u8>>;
+
+fn decompressor(content_encoding: &str) -> Option<Decompress> {
+    match content_encoding {
+        #[cfg(feature = "zstd")]
+        Zstd::CONTENT_ENCODING => Some(Zstd::decompress),
+        #[cfg(feature = "lz4")]
+        Lz4::CONTENT_ENCODING => Some(Lz4::decompress),
+        #[cfg(feature = "gzip")]
+        Gzip::CONTENT_ENCODING => Some(Gzip::decompress),
+        _ => None,
+    }
+}
+
+const SUPPORTED_ENCODINGS: &[&str] = &[
+    #[cfg(feature = "zstd")]
+    Zstd::CONTENT_ENCODING,
+    #[cfg(feature = "lz4")]
+    Lz4::CONTENT_ENCODING,
+    #[cfg(feature = "gzip")]
+    Gzip::CONTENT_ENCODING,
+];
+
+/// Packs with `P`, compressing bodies of at least `THRESHOLD` bytes with `C`.
+///
+/// Every packer decompresses whatever it unpacks according to the message's content encoding, so
+/// compressed and uncompressed messages can share a stream, and consumers don't need to know
+/// which codec producers picked. Bodies are inflated up to `P::MAX_DECOMPRESSED_SIZE`. Wrap it in `Encrypted`, not the other way around, ciphertext
+/// doesn't compress. Compressing an encrypting packer fails to build.
+#[derive(Debug)]
+pub struct Compressed<P: Packer, C: Codec, const THRESHOLD: usize = 1024>(PhantomData<(P, C)>);
+
+impl<P: Packer, C: Codec, const THRESHOLD: usize> Compressed<P, C, THRESHOLD> {
+    /// Decoding decrypts last, after the body would have had to be decompressed
+    const NOT_ENCRYPTED: () = assert!(
+        !P::ENCRYPTS,
+        "Compressed can't wrap an encrypting packer, wrap it in Encrypted instead"
+    );
+}
+
+impl<P: Packer, C: Codec, const THRESHOLD: usize> Packer for Compressed<P, C, THRESHOLD> {
+    const CONTENT_TYPE: &'static str = P::CONTENT_TYPE;
+    const MAX_DECOMPRESSED_SIZE: usize = P::MAX_DECOMPRESSED_SIZE;
+
+    fn ser<Payload: Serialize>(payload: &Payload) -> PackerResult<Vec<u8>> {
+        P::ser(payload)
+    }
+
+    fn de<Payload: DeserializeOwned>(bytes: &[u8]) -> PackerResult<Payload> {
+        P::de(bytes)
+    }
+
+    fn de_message<Payload: DeserializeOwned>(
+        message: &Message,
+        bytes: &[u8],
+    ) -> PackerResult<Payload> {
+        P::de_message(message, bytes)
+    }
+
+    fn encode(bytes: Vec<u8>, properties: &[(&'static str, SimpleValue)]) -> PackerResult<Encoded> {
+        let () = Self::NOT_ENCRYPTED;
+        let encoded = P::encode(bytes, properties)?;
+        if encoded.content_encoding.is_some() || encoded.bytes.len() < THRESHOLD {
+            return Ok(encoded);
+        }
+        let compressed =
+            C::compress(&encoded.bytes).map_err(|e| PackerError::Compress(e.into()))?;
+        // Some bodies don't shrink, there's no point making consumers decompress those
+        if compressed.len() >= encoded.bytes.len() {
+            return Ok(encoded);
+        }
+        Ok(Encoded {
+            bytes: compressed,
+            content_encoding: Some(C::CONTENT_ENCODING),
+            ..encoded
+        })
+    }
+
+    fn decode<'a>(message: &Message, bytes: Cow<'a, [u8]>) -> PackerResult<Cow<'a, [u8]>> {
+        let () = Self::NOT_ENCRYPTED;
+        P::decode(message, bytes)
+    }
+
+    fn validate(message: &Message) -> PackerResult<()> {
+        P::validate(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+    use crate::pack::JsonPacker;
+
+    #[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+    fn round_trips<C: Codec>() {
+        let bytes = b"compress me ".repeat(100);
+        let compressed = C::compress(&bytes).unwrap();
+        assert!(compressed.len() < bytes.len());
+        assert_eq!(
+            decompress(C::CONTENT_ENCODING, &compressed, bytes.len()).unwrap(),
+            bytes
+        );
+        assert!(matches!(
+            decompress(C::CONTENT_ENCODING, &compressed, bytes.len() - 1),
+            Err(PackerError::TooLarge { .. })
+        ));
+    }
+
+    #[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+    fn packs<C: Codec>() {
+        let payload = vec!["compress me"; 100];
+        let message = Compressed::<JsonPacker, C>::pack(&payload).unwrap();
+        let content_encoding = message.properties().unwrap().content_encoding.as_ref();
+        assert_eq!(
+            content_encoding.map(|encoding| encoding.as_str()),
+            Some(C::CONTENT_ENCODING)
+        );
+        // Unpacking goes by the content encoding, whichever packer does it
+        let unpacked: Vec<String> = JsonPacker::unpack(&message).unwrap();
+        assert_eq!(unpacked, payload);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd() {
+        round_trips::<Zstd>();
+        packs::<Zstd>();
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() {
+        round_trips::<Lz4>();
+        packs::<Lz4>();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip() {
+        round_trips::<Gzip>();
+        packs::<Gzip>();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn small_bodies_stay_uncompressed() {
+        let message = Compressed::<JsonPacker, Zstd>::pack("small").unwrap();
+        assert!(message.properties().unwrap().content_encoding.is_none());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn rejects_bodies_inflating_past_the_limit() {
+        #[derive(Debug)]
+        struct Small;
+        impl Packer for Small {
+            const CONTENT_TYPE: &'static str = JsonPacker::CONTENT_TYPE;
+            const MAX_DECOMPRESSED_SIZE: usize = 1024;
+
+            fn ser<Payload: Serialize>(payload: &Payload) -> PackerResult<Vec<u8>> {
+                JsonPacker::ser(payload)
+            }
+
+            fn de<Payload: DeserializeOwned>(bytes: &[u8]) -> PackerResult<Payload> {
+                JsonPacker::de(bytes)
+            }
+        }
+
+        let payload = vec!["compress me"; 1000];
+        let message = Compressed::<JsonPacker, Zstd>::pack(&payload).unwrap();
+        assert!(message.data().unwrap().len() < 1024);
+        assert!(matches!(
+            Small::unpack::<Vec<String>>(&message),
+            Err(PackerError::TooLarge { limit: 1024 })
+        ));
+        assert!(JsonPacker::unpack::<Vec<String>>(&message).is_ok());
+    }
+
+    #[test]
+    fn unknown_encodings_fail() {
+        assert!(decompress("brotli", b"", 1024).is_err());
+    }
+}
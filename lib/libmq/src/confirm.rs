@@ -0,0 +1,216 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::{metrics, transport::SendOutcome};
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, valuable::Valuable)]
+#[serde(tag = "$type", content = "reason")]
+pub enum ConfirmError {
+    #[error("The broker rejected the message with response code {code}")]
+    #[serde(rename = "dev.thmsn.mq.confirm.rejected")]
+    Rejected { code: u16 },
+    #[error("{max} messages are already waiting for the broker to confirm them")]
+    #[serde(rename = "dev.thmsn.mq.confirm.backlog_full")]
+    BacklogFull { max: usize },
+    #[error("The producer went away before the broker confirmed the message")]
+    #[serde(rename = "dev.thmsn.mq.confirm.lost")]
+    Lost,
+}
+
+/// What to do about messages sent without waiting for their confirm, once too many of them are
+/// still waiting for one
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum BacklogPolicy {
+    #[default]
+    Unbounded,
+    /// Hold further sends until the backlog is back under `max`
+    Block { max: usize },
+    /// Fail further sends with `ConfirmError::BacklogFull` until the backlog is back under `max`
+    Reject { max: usize },
+}
+
+/// Resolves once the broker has confirmed or rejected a message, for sends that don't wait
+/// on it themselves
+pub struct ConfirmHandle(BoxFuture<'static, SendOutcome>);
+impl ConfirmHandle {
+    pub(crate) fn new(confirmed: oneshot::Receiver<SendOutcome>) -> Self {
+        Self(
+            confirmed
+                .map(|outcome| outcome.unwrap_or(SendOutcome::Failed(ConfirmError::Lost.into())))
+                .boxed(),
+        )
+    }
+
+    pub(crate) fn settled(outcome: SendOutcome) -> Self {
+        Self(futures::future::ready(outcome).boxed())
+    }
+
+    pub(crate) fn all(handles: Vec<ConfirmHandle>) -> Self {
+        Self(
+            async move {
+                for handle in handles {
+                    let outcome = handle.await;
+                    if !outcome.is_success() {
+                        return outcome;
+                    }
+                }
+                SendOutcome::Confirmed
+            }
+            .boxed(),
+        )
+    }
+}
+impl Future for ConfirmHandle {
+    type Output = SendOutcome;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.poll_unpin(cx)
+    }
+}
+impl std::fmt::Debug for ConfirmHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConfirmHandle").finish_non_exhaustive()
+    }
+}
+
+pub(crate) struct Backlog {
+    stream: String,
+    policy: BacklogPolicy,
+    slots: Option<Arc<Semaphore>>,
+}
+impl Backlog {
+    pub fn new(stream: &str, policy: &BacklogPolicy) -> Self {
+        let slots = match policy {
+            BacklogPolicy::Unbounded => None,
+            BacklogPolicy::Block { max } | BacklogPolicy::Reject { max } => {
+                Some(Arc::new(Semaphore::new(*max)))
+            }
+        };
+        Self {
+            stream: stream.to_string(),
+            policy: policy.clone(),
+            slots,
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, ConfirmError> {
+        let Some(slots) = &self.slots else {
+            return Ok(None);
+        };
+        let permit = match self.policy {
+            BacklogPolicy::Reject { max } => slots.clone().try_acquire_owned().map_err(|_| {
+                tracing::warn!(stream = self.stream, max, "Confirm backlog is full");
+                metrics::backlog_full(&self.stream);
+                ConfirmError::BacklogFull { max }
+            })?,
+            _ => slots
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| ConfirmError::Lost)?,
+        };
+        Ok(Some(permit))
+    }
+}
+
+// A publish may be retried, so only the first confirm to arrive counts
+pub(crate) struct Tracker {
+    stream: String,
+    waiting: Mutex<Option<Waiting>>,
+}
+type Waiting = (
+    Option<OwnedSemaphorePermit>,
+    Option<oneshot::Sender<SendOutcome>>,
+);
+impl Tracker {
+    pub fn new(
+        stream: &str,
+        slot: Option<OwnedSemaphorePermit>,
+        handle: Option<oneshot::Sender<SendOutcome>>,
+    ) -> Self {
+        Self {
+            stream: stream.to_string(),
+            waiting: Mutex::new(Some((slot, handle))),
+        }
+    }
+
+    pub fn settle(&self, outcome: SendOutcome) {
+        let Some((_slot, handle)) = self
+            .waiting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+        else {
+            return;
+        };
+        match &outcome {
+            SendOutcome::Rejected { code } => rejected(&self.stream, *code),
+            SendOutcome::Failed(e) => {
+                tracing::warn!(stream = self.stream, "Publish was never confirmed: {e}")
+            }
+            SendOutcome::Sent | SendOutcome::Confirmed => {}
+        }
+        if let Some(handle) = handle {
+            // Nobody is listening if the handle was dropped
+            let _ = handle.send(outcome);
+        }
+    }
+}
+
+pub(crate) fn rejected(stream: &str, code: u16) {
+    tracing::warn!(stream, code, "Broker rejected a publish");
+    metrics::rejected_publish(stream, code);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::metrics::tests::recorded;
+
+    #[tokio::test]
+    async fn reject_fails_sends_past_the_backlog() {
+        let rejected = || recorded("mq.producer.rejected_publishes", "rejecting");
+        assert_eq!(rejected(), 0);
+        let backlog = Backlog::new("rejecting", &BacklogPolicy::Reject { max: 1 });
+
+        let first = Tracker::new("rejecting", backlog.acquire().await.unwrap(), None);
+        assert!(matches!(
+            backlog.acquire().await,
+            Err(ConfirmError::BacklogFull { max: 1 })
+        ));
+        assert_eq!(rejected(), 1);
+
+        first.settle(SendOutcome::Confirmed);
+        assert!(backlog.acquire().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_a_confirm() {
+        let backlog = Backlog::new("blocking", &BacklogPolicy::Block { max: 1 });
+        let (handle, confirmed) = oneshot::channel();
+        let first = Tracker::new("blocking", backlog.acquire().await.unwrap(), Some(handle));
+
+        let mut second = std::pin::pin!(backlog.acquire());
+        assert!(tokio::time::timeout(Duration::from_millis(20), &mut second)
+            .await
+            .is_err());
+
+        first.settle(SendOutcome::Confirmed);
+        assert!(second.await.unwrap().is_some());
+        assert!(matches!(
+            ConfirmHandle::new(confirmed).await,
+            SendOutcome::Confirmed
+        ));
+    }
+}
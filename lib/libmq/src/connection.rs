@@ -0,0 +1,200 @@
+use std::{
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use liberror::AnyError;
+use rabbitmq_stream_client::{Client, ClientOptions, Environment, RabbitMQStreamResult};
+use strum::Display;
+use tokio::sync::watch;
+
+use crate::channel::ChannelConfiguration;
+
+/// How to go about getting a lost connection back
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt, doubled after every failed one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many attempts, or keep trying forever when unset
+    pub max_attempts: Option<u32>,
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum ConnectionStatus {
+    Connected,
+    /// Something lost its connection and is being rebuilt
+    Reconnecting,
+    /// Reconnecting ran out of attempts, nothing more will be tried
+    Failed,
+}
+
+fn client_options(config: &ChannelConfiguration, host: &str, port: u16) -> ClientOptions {
+    let mut options = ClientOptions::builder()
+        .host(host)
+        .port(port)
+        .load_balancer_mode(config.load_balancer_mode);
+    if let Some(credentials) = &config.credentials {
+        options = options
+            .user(&credentials.username)
+            .password(credentials.password.expose());
+    }
+    if let Some(virtual_host) = &config.virtual_host {
+        options = options.v_host(virtual_host);
+    }
+    if let Some(tls) = &config.tls {
+        options = options.tls(tls.into());
+    }
+    options.build()
+}
+
+async fn connect_environment(
+    config: &ChannelConfiguration,
+    host: &str,
+    port: u16,
+) -> RabbitMQStreamResult<Environment> {
+    let mut environment = Environment::builder()
+        .host(host)
+        .port(port)
+        .load_balancer_mode(config.load_balancer_mode);
+    if let Some(credentials) = &config.credentials {
+        environment = environment
+            .username(&credentials.username)
+            .password(credentials.password.expose());
+    }
+    if let Some(virtual_host) = &config.virtual_host {
+        environment = environment.virtual_host(virtual_host);
+    }
+    if let Some(tls) = &config.tls {
+        environment = environment.tls(tls.into());
+    }
+    environment.build().await
+}
+
+/// Connect to the first of the channel's hosts that answers
+pub(crate) async fn build_environment(
+    config: &ChannelConfiguration,
+) -> Result<Environment, AnyError> {
+    let mut endpoints = config.endpoints();
+    let (host, port) = endpoints.next().unwrap_or((&config.host, config.port));
+    let mut environment = connect_environment(config, host, port).await;
+    for (host, port) in endpoints {
+        match environment {
+            Ok(_) => break,
+            Err(e) => tracing::warn!(host, port, "Failed to connect, trying the next host: {e}"),
+        }
+        environment = connect_environment(config, host, port).await;
+    }
+    Ok(environment?)
+}
+
+/// The environment producers and consumers are built from, rebuilt when one of them finds the
+/// broker has gone away
+pub(crate) struct Connection {
+    config: ChannelConfiguration,
+    environment: RwLock<Environment>,
+    status: watch::Sender<ConnectionStatus>,
+}
+impl Connection {
+    pub async fn connect(config: &ChannelConfiguration) -> Result<Arc<Self>, AnyError> {
+        let environment = build_environment(config).await?;
+        Ok(Arc::new(Self {
+            config: config.clone(),
+            environment: RwLock::new(environment),
+            status: watch::Sender::new(ConnectionStatus::Connected),
+        }))
+    }
+
+    /// A connection of its own, for the commands `Environment` doesn't expose
+    pub async fn client(&self) -> Result<Client, AnyError> {
+        let mut endpoints = self.config.endpoints();
+        let (host, port) = endpoints
+            .next()
+            .unwrap_or((&self.config.host, self.config.port));
+        let mut client = Client::connect(client_options(&self.config, host, port)).await;
+        for (host, port) in endpoints {
+            if client.is_ok() {
+                break;
+            }
+            client = Client::connect(client_options(&self.config, host, port)).await;
+        }
+        Ok(client?)
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.environment
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        *self.status.borrow()
+    }
+
+    pub fn status_changes(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+
+    fn set_status(&self, status: ConnectionStatus) {
+        let previous = self.status.send_replace(status);
+        if previous != status {
+            tracing::info!(%previous, %status, "Connection status changed");
+        }
+    }
+
+    /// Rebuild the environment and then `what` from it, backing off between attempts until
+    /// both succeed or the reconnect policy gives up
+    pub async fn recover<T, E, F, Fut>(&self, what: &str, mut build: F) -> Result<T, AnyError>
+    where
+        F: FnMut(Environment) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Into<AnyError>,
+    {
+        let policy = &self.config.reconnect;
+        let mut backoff = policy.initial_backoff;
+        let mut attempt = 0;
+        self.set_status(ConnectionStatus::Reconnecting);
+
+        loop {
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+
+            let rebuilt = match build_environment(&self.config).await {
+                Ok(environment) => build(environment.clone())
+                    .await
+                    .map(|built| (environment, built))
+                    .map_err(Into::into),
+                Err(e) => Err(e),
+            };
+
+            match rebuilt {
+                Ok((environment, built)) => {
+                    *self.environment.write().unwrap_or_else(|e| e.into_inner()) = environment;
+                    tracing::info!(what, attempt, "Recovered");
+                    self.set_status(ConnectionStatus::Connected);
+                    return Ok(built);
+                }
+                Err(e) if policy.max_attempts.is_some_and(|max| attempt >= max) => {
+                    tracing::error!(what, attempt, "Giving up on recovery: {e}");
+                    self.set_status(ConnectionStatus::Failed);
+                    return Err(e);
+                }
+                Err(e) => {
+                    tracing::warn!(what, attempt, ?backoff, "Recovery failed: {e}");
+                    backoff = (backoff * 2).min(policy.max_backoff);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,558 @@
+use std::{borrow::Cow, io, marker::PhantomData};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use rabbitmq_stream_client::types::{Message, SimpleValue};
+use rabbitmq_stream_protocol::codec::Encoder;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    meta,
+    pack::{self, Encoded, Packer, PackerError, PackerResult, PackerValidateError},
+    schema::{ENVELOPE_VERSION_PROPERTY, SCHEMA_VERSION_PROPERTY},
+};
+
+const KEY_ID_PROPERTY: &str = "x-encryption-key-id";
+const ENCRYPTION_ALGORITHM_PROPERTY: &str = "x-encryption-algorithm";
+const ENCRYPTION_ALGORITHM: &str = "aes-256-gcm";
+
+const SIGNATURE_PROPERTY: &str = "x-signature";
+const SIGNATURE_KEY_ID_PROPERTY: &str = "x-signature-key-id";
+const SIGNATURE_ALGORITHM_PROPERTY: &str = "x-signature-algorithm";
+
+/// Length of the nonce that leads every encrypted body
+const NONCE_SIZE: usize = 12;
+
+pub type Key = [u8; 32];
+
+/// Where keys are looked up by id. Keep retired keys around for as long as messages encrypted
+/// or signed with them are still on the stream, so rotating keys doesn't strand older messages.
+///
+/// Packers are types, not values, so the ring has no instance to hold keys either. Keys loaded at
+/// runtime go in a static the implementation reads, such as a `RwLock` filled from configuration
+/// at startup and replaced to rotate.
+pub trait KeyRing: std::fmt::Debug + Send + Sync + Unpin + 'static {
+    /// Id and key new messages are encrypted or signed with
+    fn current() -> (String, Key);
+    fn get(key_id: &str) -> Option<Key>;
+}
+
+/// Packs with `P`, then encrypts the body with AES-256-GCM under the keyring's current key. The
+/// key id travels in the application properties, and is bound to the ciphertext along with the
+/// content type and encoding, and the envelope, schema and meta properties.
+#[derive(Debug)]
+pub struct Encrypted<P: Packer, K: KeyRing>(PhantomData<(P, K)>);
+
+impl<P: Packer, K: KeyRing> Packer for Encrypted<P, K> {
+    const CONTENT_TYPE: &'static str = P::CONTENT_TYPE;
+    const MAX_DECOMPRESSED_SIZE: usize = P::MAX_DECOMPRESSED_SIZE;
+    const ENCRYPTS: bool = true;
+
+    fn ser<Payload: Serialize>(payload: &Payload) -> PackerResult<Vec<u8>> {
+        P::ser(payload)
+    }
+
+    fn de<Payload: DeserializeOwned>(bytes: &[u8]) -> PackerResult<Payload> {
+        P::de(bytes)
+    }
+
+    fn de_message<Payload: DeserializeOwned>(
+        message: &Message,
+        bytes: &[u8],
+    ) -> PackerResult<Payload> {
+        P::de_message(message, bytes)
+    }
+
+    fn encode(bytes: Vec<u8>, properties: &[(&'static str, SimpleValue)]) -> PackerResult<Encoded> {
+        let mut encoded = P::encode(bytes, properties)?;
+        let (key_id, key) = K::current();
+        let aad = authenticated(
+            |name| lookup(properties, name),
+            (Self::CONTENT_TYPE, encoded.content_encoding),
+            key_id.as_bytes(),
+        )?;
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(&key.into())
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &encoded.bytes,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| PackerError::Encrypt(e.to_string()))?;
+
+        encoded.bytes = [nonce.as_slice(), &ciphertext].concat();
+        encoded
+            .properties
+            .push((KEY_ID_PROPERTY, SimpleValue::String(key_id)));
+        encoded
+            .properties
+            .push((ENCRYPTION_ALGORITHM_PROPERTY, ENCRYPTION_ALGORITHM.into()));
+        Ok(encoded)
+    }
+
+    fn decode<'a>(message: &Message, bytes: Cow<'a, [u8]>) -> PackerResult<Cow<'a, [u8]>> {
+        let algorithm = string_property(message, ENCRYPTION_ALGORITHM_PROPERTY)?;
+        if algorithm != ENCRYPTION_ALGORITHM {
+            return Err(PackerValidateError::IncorrectPropertyValue {
+                property_name: ENCRYPTION_ALGORITHM_PROPERTY.into(),
+                expected_value: ENCRYPTION_ALGORITHM.into(),
+                actual_value: algorithm.into(),
+            }
+            .into());
+        }
+        let key_id = string_property(message, KEY_ID_PROPERTY)?;
+        let key = K::get(key_id).ok_or_else(|| PackerValidateError::UnknownKey {
+            key_id: key_id.to_string(),
+        })?;
+
+        if bytes.len() < NONCE_SIZE {
+            return Err(PackerError::Decrypt("body is shorter than a nonce".into()));
+        }
+        let aad = authenticated(
+            |name| message_property(message, name),
+            content(message)?,
+            key_id.as_bytes(),
+        )?;
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+        let plaintext = Aes256Gcm::new(&key.into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| PackerError::Decrypt(e.to_string()))?;
+
+        P::decode(message, Cow::Owned(plaintext))
+    }
+
+    fn validate(message: &Message) -> PackerResult<()> {
+        P::validate(message)
+    }
+}
+
+/// Signs message bodies, and checks the signatures of others
+pub trait Signer: std::fmt::Debug + Send + Sync + Unpin + 'static {
+    const ALGORITHM: &'static str;
+
+    /// Sign with the current key, returning its id along with the signature
+    fn sign(bytes: &[u8]) -> (String, Vec<u8>);
+    fn verify(key_id: &str, bytes: &[u8], signature: &[u8]) -> Result<(), String>;
+}
+
+/// HMAC-SHA256 with keys shared between producers and consumers
+#[derive(Debug)]
+pub struct HmacSha256<K: KeyRing>(PhantomData<K>);
+
+impl<K: KeyRing> HmacSha256<K> {
+    fn mac(key: &Key, bytes: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(bytes);
+        mac
+    }
+}
+
+impl<K: KeyRing> Signer for HmacSha256<K> {
+    const ALGORITHM: &'static str = "hmac-sha256";
+
+    fn sign(bytes: &[u8]) -> (String, Vec<u8>) {
+        let (key_id, key) = K::current();
+        let signature = Self::mac(&key, bytes).finalize().into_bytes().to_vec();
+        (key_id, signature)
+    }
+
+    fn verify(key_id: &str, bytes: &[u8], signature: &[u8]) -> Result<(), String> {
+        let key = K::get(key_id).ok_or_else(|| format!("unknown key \"{key_id}\""))?;
+        Self::mac(&key, bytes)
+            .verify_slice(signature)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Ed25519 key pairs, where consumers only need the public halves. Read from a static like
+/// `KeyRing`.
+pub trait Ed25519Keys: std::fmt::Debug + Send + Sync + Unpin + 'static {
+    /// Id and key new messages are signed with
+    fn current() -> (String, SigningKey);
+    fn get(key_id: &str) -> Option<VerifyingKey>;
+}
+
+#[derive(Debug)]
+pub struct Ed25519<K: Ed25519Keys>(PhantomData<K>);
+
+impl<K: Ed25519Keys> Signer for Ed25519<K> {
+    const ALGORITHM: &'static str = "ed25519";
+
+    fn sign(bytes: &[u8]) -> (String, Vec<u8>) {
+        let (key_id, key) = K::current();
+        let signature = ed25519_dalek::Signer::sign(&key, bytes);
+        (key_id, signature.to_vec())
+    }
+
+    fn verify(key_id: &str, bytes: &[u8], signature: &[u8]) -> Result<(), String> {
+        let key = K::get(key_id).ok_or_else(|| format!("unknown key \"{key_id}\""))?;
+        let signature = Signature::from_slice(signature).map_err(|e| e.to_string())?;
+        key.verify_strict(bytes, &signature)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Packs with `P`, then signs the body as it goes on the wire with `S`, along with the content
+/// type and encoding, and the envelope, schema and meta properties. Anything unsigned, or whose signature doesn't check out, fails
+/// validation.
+#[derive(Debug)]
+pub struct Signed<P: Packer, S: Signer>(PhantomData<(P, S)>);
+
+impl<P: Packer, S: Signer> Packer for Signed<P, S> {
+    const CONTENT_TYPE: &'static str = P::CONTENT_TYPE;
+    const MAX_DECOMPRESSED_SIZE: usize = P::MAX_DECOMPRESSED_SIZE;
+    const ENCRYPTS: bool = P::ENCRYPTS;
+
+    fn ser<Payload: Serialize>(payload: &Payload) -> PackerResult<Vec<u8>> {
+        P::ser(payload)
+    }
+
+    fn de<Payload: DeserializeOwned>(bytes: &[u8]) -> PackerResult<Payload> {
+        P::de(bytes)
+    }
+
+    fn de_message<Payload: DeserializeOwned>(
+        message: &Message,
+        bytes: &[u8],
+    ) -> PackerResult<Payload> {
+        P::de_message(message, bytes)
+    }
+
+    fn encode(bytes: Vec<u8>, properties: &[(&'static str, SimpleValue)]) -> PackerResult<Encoded> {
+        let mut encoded = P::encode(bytes, properties)?;
+        let signed = authenticated(
+            |name| lookup(properties, name),
+            (Self::CONTENT_TYPE, encoded.content_encoding),
+            &encoded.bytes,
+        )?;
+        let (key_id, signature) = S::sign(&signed);
+        encoded
+            .properties
+            .push((SIGNATURE_PROPERTY, SimpleValue::Binary(signature)));
+        encoded
+            .properties
+            .push((SIGNATURE_KEY_ID_PROPERTY, SimpleValue::String(key_id)));
+        encoded
+            .properties
+            .push((SIGNATURE_ALGORITHM_PROPERTY, S::ALGORITHM.into()));
+        Ok(encoded)
+    }
+
+    fn decode<'a>(message: &Message, bytes: Cow<'a, [u8]>) -> PackerResult<Cow<'a, [u8]>> {
+        let key_id = string_property(message, SIGNATURE_KEY_ID_PROPERTY)?;
+        let invalid = |reason: String| PackerValidateError::InvalidSignature {
+            key_id: key_id.to_string(),
+            reason,
+        };
+
+        let algorithm = string_property(message, SIGNATURE_ALGORITHM_PROPERTY)?;
+        if algorithm != S::ALGORITHM {
+            return Err(invalid(format!("expected {} got {algorithm}", S::ALGORITHM)).into());
+        }
+        let signature = match property(message, SIGNATURE_PROPERTY)? {
+            SimpleValue::Binary(signature) => signature,
+            value => return Err(invalid(format!("signature is {value:?}")).into()),
+        };
+        let signed = authenticated(
+            |name| message_property(message, name),
+            content(message)?,
+            &bytes,
+        )?;
+        S::verify(key_id, &signed, signature).map_err(invalid)?;
+
+        P::decode(message, bytes)
+    }
+
+    fn validate(message: &Message) -> PackerResult<()> {
+        P::validate(message)
+    }
+}
+
+/// `data` along with the content type and encoding, and the envelope, schema and meta properties
+/// `property` finds, for signing and encrypting packers to authenticate so none of them can be
+/// swapped or moved between messages
+fn authenticated<'a>(
+    property: impl Fn(&str) -> Option<&'a SimpleValue>,
+    (content_type, content_encoding): (&str, Option<&str>),
+    data: &[u8],
+) -> PackerResult<Vec<u8>> {
+    let names = [ENVELOPE_VERSION_PROPERTY, SCHEMA_VERSION_PROPERTY];
+    let mut bytes = vec![];
+    for value in [content_type, content_encoding.unwrap_or_default()] {
+        bytes.extend((value.len() as u32).to_be_bytes());
+        bytes.extend(value.as_bytes());
+    }
+    for name in names.iter().chain(meta::PROPERTIES) {
+        // The properties map has no order, so each is encoded on its own
+        let encoded = match property(name) {
+            Some(value) => {
+                let message = Message::builder()
+                    .application_properties()
+                    .insert(*name, value.clone())
+                    .message_builder()
+                    .build();
+                let mut encoded = Vec::with_capacity(message.encoded_size() as usize);
+                message.encode(&mut encoded).map_err(|e| {
+                    PackerError::Serialize(io::Error::other(format!("{e:?}")).into())
+                })?;
+                encoded
+            }
+            None => vec![],
+        };
+        bytes.extend((encoded.len() as u32).to_be_bytes());
+        bytes.extend(encoded);
+    }
+    bytes.extend(data);
+    Ok(bytes)
+}
+
+fn content(message: &Message) -> PackerResult<(&str, Option<&str>)> {
+    let content_encoding = message
+        .properties()
+        .and_then(|properties| properties.content_encoding.as_ref());
+    Ok((
+        pack::content_type(message)?,
+        content_encoding.map(|encoding| encoding.as_str()),
+    ))
+}
+
+fn lookup<'a>(properties: &'a [(&str, SimpleValue)], name: &str) -> Option<&'a SimpleValue> {
+    properties
+        .iter()
+        .find(|(property_name, _)| *property_name == name)
+        .map(|(_, value)| value)
+}
+
+fn message_property<'a>(message: &'a Message, name: &str) -> Option<&'a SimpleValue> {
+    message
+        .application_properties()
+        .and_then(|properties| properties.get(name))
+}
+
+fn property<'a>(message: &'a Message, property_name: &str) -> PackerResult<&'a SimpleValue> {
+    message_property(message, property_name).ok_or_else(|| {
+        PackerValidateError::MissingProperty {
+            property_name: property_name.into(),
+        }
+        .into()
+    })
+}
+
+fn string_property<'a>(message: &'a Message, property_name: &str) -> PackerResult<&'a str> {
+    match property(message, property_name)? {
+        SimpleValue::String(value) => Ok(value),
+        value => Err(PackerValidateError::IncorrectPropertyValue {
+            property_name: property_name.into(),
+            expected_value: "string".into(),
+            actual_value: format!("{value:?}"),
+        }
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        meta::ManagerMeta,
+        pack::{JsonPacker, PackOptions},
+    };
+
+    #[derive(Debug)]
+    struct Keys;
+    impl KeyRing for Keys {
+        fn current() -> (String, Key) {
+            ("2".into(), [2; 32])
+        }
+
+        fn get(key_id: &str) -> Option<Key> {
+            match key_id {
+                "1" => Some([1; 32]),
+                "2" => Some([2; 32]),
+                _ => None,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct PairKeys;
+    impl Ed25519Keys for PairKeys {
+        fn current() -> (String, SigningKey) {
+            ("a".into(), SigningKey::from_bytes(&[9; 32]))
+        }
+
+        fn get(key_id: &str) -> Option<VerifyingKey> {
+            (key_id == "a").then(|| SigningKey::from_bytes(&[9; 32]).verifying_key())
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Payload {
+        text: String,
+    }
+
+    fn pack<P: Packer>() -> Message {
+        let options = PackOptions {
+            meta: Some(ManagerMeta::new("alice")),
+            ..PackOptions::default()
+        };
+        P::pack_with(
+            Payload {
+                text: "hello".repeat(100),
+            },
+            &options,
+        )
+        .unwrap()
+    }
+
+    /// `message` with its application property `name` set to `value`
+    fn with_property(message: &Message, name: &str, value: SimpleValue) -> Message {
+        let properties = message.properties().unwrap();
+        let builder = Message::builder()
+            .body(message.data().unwrap().to_vec())
+            .properties()
+            .content_type(properties.content_type.as_ref().unwrap().as_str());
+        let builder = match &properties.content_encoding {
+            Some(content_encoding) => builder.content_encoding(content_encoding.as_str()),
+            None => builder,
+        };
+        message
+            .application_properties()
+            .unwrap()
+            .iter()
+            .filter(|(property_name, _)| *property_name != name)
+            .fold(
+                builder.message_builder().application_properties(),
+                |builder, (property_name, value)| {
+                    builder.insert(property_name.as_str(), value.clone())
+                },
+            )
+            .insert(name, value)
+            .message_builder()
+            .build()
+    }
+
+    fn round_trips<P: Packer>() {
+        let payload: Payload = P::unpack(&pack::<P>()).unwrap();
+        assert_eq!(payload.text, "hello".repeat(100));
+    }
+
+    fn rejects_moved_properties<P: Packer>() {
+        let unrelated = with_property(&pack::<P>(), "x-note", SimpleValue::Uint(1));
+        assert!(P::unpack::<Payload>(&unrelated).is_ok());
+        let forged = with_property(
+            &pack::<P>(),
+            "x-origin",
+            SimpleValue::String("mallory".into()),
+        );
+        assert!(P::unpack::<Payload>(&forged).is_err());
+        let forged = with_property(
+            &pack::<P>(),
+            "x-deadline",
+            SimpleValue::String("2000-01-01T00:00:00Z".into()),
+        );
+        assert!(P::unpack::<Payload>(&forged).is_err());
+    }
+
+    #[test]
+    fn encrypted_round_trips() {
+        round_trips::<Encrypted<JsonPacker, Keys>>();
+        assert_ne!(
+            pack::<Encrypted<JsonPacker, Keys>>().data(),
+            pack::<JsonPacker>().data()
+        );
+    }
+
+    #[test]
+    fn encrypted_binds_its_properties() {
+        rejects_moved_properties::<Encrypted<JsonPacker, Keys>>();
+        let forged = with_property(
+            &pack::<Encrypted<JsonPacker, Keys>>(),
+            KEY_ID_PROPERTY,
+            SimpleValue::String("1".into()),
+        );
+        assert!(Encrypted::<JsonPacker, Keys>::unpack::<Payload>(&forged).is_err());
+    }
+
+    #[test]
+    fn signed_round_trips() {
+        round_trips::<Signed<JsonPacker, HmacSha256<Keys>>>();
+        round_trips::<Signed<JsonPacker, Ed25519<PairKeys>>>();
+        round_trips::<Signed<Encrypted<JsonPacker, Keys>, Ed25519<PairKeys>>>();
+    }
+
+    #[test]
+    fn signed_binds_its_properties() {
+        rejects_moved_properties::<Signed<JsonPacker, HmacSha256<Keys>>>();
+        rejects_moved_properties::<Signed<JsonPacker, Ed25519<PairKeys>>>();
+    }
+
+    #[test]
+    fn binds_the_content_encoding() {
+        let recoded = |message: &Message| {
+            let properties = message.properties().unwrap();
+            let builder = Message::builder()
+                .body(message.data().unwrap().to_vec())
+                .properties()
+                .content_type(properties.content_type.as_ref().unwrap().as_str())
+                .content_encoding("gzip")
+                .message_builder();
+            message
+                .application_properties()
+                .unwrap()
+                .iter()
+                .fold(
+                    builder.application_properties(),
+                    |builder, (name, value)| builder.insert(name.as_str(), value.clone()),
+                )
+                .message_builder()
+                .build()
+        };
+
+        let forged = recoded(&pack::<Encrypted<JsonPacker, Keys>>());
+        assert!(matches!(
+            Encrypted::<JsonPacker, Keys>::unpack::<Payload>(&forged),
+            Err(PackerError::Decrypt(_))
+        ));
+        let forged = recoded(&pack::<Signed<JsonPacker, HmacSha256<Keys>>>());
+        assert!(matches!(
+            Signed::<JsonPacker, HmacSha256<Keys>>::unpack::<Payload>(&forged),
+            Err(PackerError::Validation(
+                PackerValidateError::InvalidSignature { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn unsigned_messages_fail() {
+        assert!(
+            Signed::<JsonPacker, HmacSha256<Keys>>::unpack::<Payload>(&pack::<JsonPacker>())
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn encrypts_compressed_bodies() {
+        use crate::compression::{Compressed, Zstd};
+
+        round_trips::<Encrypted<Compressed<JsonPacker, Zstd, 16>, Keys>>();
+    }
+}
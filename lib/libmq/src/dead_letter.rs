@@ -0,0 +1,373 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::StreamExt;
+use liberror::AnyError;
+use rabbitmq_stream_client::types::{Message, SimpleValue};
+use rabbitmq_stream_protocol::codec::{Decoder, Encoder};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    channel::ChannelConfiguration,
+    offset::StartPosition,
+    pack::{PackOptions, PackerError},
+    retention::StreamSetupError,
+    transport::{PublishError, RabbitMqTransport, Transport, TransportDelivery, TransportProducer},
+};
+
+const STREAM_PROPERTY: &str = "x-dead-letter-stream";
+const OFFSET_PROPERTY: &str = "x-dead-letter-offset";
+const ERROR_PROPERTY: &str = "x-dead-letter-error";
+const TIMESTAMP_PROPERTY: &str = "x-dead-letter-timestamp";
+
+const LIST_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, valuable::Valuable)]
+#[serde(
+    tag = "$type",
+    content = "reason",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum DeadLetterError {
+    #[error("The channel has no dead-letter stream")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.disabled")]
+    Disabled,
+    #[error("Failed to create RabbitMQ environment: {0}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.create_environment")]
+    CreateEnvironment(String),
+    #[error("Failed to set up stream: {0}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.stream")]
+    Stream(#[from] StreamSetupError),
+    #[error("Failed to create RabbitMQ stream consumer: {0}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.create_consumer")]
+    CreateConsumer(AnyError),
+    #[error("Failed to create RabbitMQ stream producer: {0}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.create_producer")]
+    CreateProducer(AnyError),
+    #[error("Failed to receive message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.receive")]
+    Receive(AnyError),
+    #[error("Failed to send message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.send")]
+    Send(AnyError),
+    #[error("Failed to encode message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.encode")]
+    Encode(String),
+    #[error("Dead letter {offset} is malformed: {reason}")]
+    #[serde(rename = "dev.thmsn.mq.dead_letter.malformed")]
+    Malformed { offset: u64, reason: String },
+}
+pub type DeadLetterResult<T> = Result<T, DeadLetterError>;
+
+impl From<PublishError> for DeadLetterError {
+    fn from(value: PublishError) -> Self {
+        match value {
+            PublishError::Pack(e) => Self::Encode(e.to_string()),
+            PublishError::Publish(e) => Self::Send(e),
+        }
+    }
+}
+
+/// A message a server couldn't unpack, as kept on the dead-letter stream
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    /// Where it is on the dead-letter stream
+    pub offset: u64,
+    /// Stream it was read from
+    pub stream: String,
+    /// Where it was on that stream
+    pub original_offset: u64,
+    pub error: PackerError,
+    pub dead_lettered_at: DateTime<Utc>,
+    pub message: Message,
+}
+impl DeadLetter {
+    // Encoded whole so nothing about the original is lost, what went wrong goes in the properties
+    fn pack(
+        stream: &str,
+        delivery: &TransportDelivery,
+        error: &PackerError,
+    ) -> DeadLetterResult<impl FnOnce(&PackOptions) -> Message> {
+        let mut body = vec![];
+        delivery
+            .message
+            .encode(&mut body)
+            .map_err(|e| DeadLetterError::Encode(format!("{e:?}")))?;
+        let error =
+            serde_json::to_string(error).map_err(|e| DeadLetterError::Encode(e.to_string()))?;
+        let (stream, offset) = (stream.to_string(), delivery.offset);
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        Ok(move |options: &PackOptions| {
+            let builder = Message::builder()
+                .body(body)
+                .application_properties()
+                .insert(STREAM_PROPERTY, stream)
+                .insert(OFFSET_PROPERTY, offset)
+                .insert(ERROR_PROPERTY, error)
+                .insert(TIMESTAMP_PROPERTY, timestamp)
+                .message_builder();
+            match options.publishing_id {
+                Some(publishing_id) => builder.publising_id(publishing_id),
+                None => builder,
+            }
+            .build()
+        })
+    }
+
+    fn unpack(delivery: TransportDelivery) -> DeadLetterResult<Self> {
+        let offset = delivery.offset;
+        let malformed = |reason: String| DeadLetterError::Malformed { offset, reason };
+        let properties = delivery
+            .message
+            .application_properties()
+            .ok_or_else(|| malformed("no application properties".to_string()))?;
+        let property = |name: &str| {
+            properties
+                .get(name)
+                .ok_or_else(|| malformed(format!("missing {name}")))
+        };
+        let text = |name: &str| match property(name)? {
+            SimpleValue::String(value) => Ok(value.clone()),
+            value => Err(malformed(format!("{name} is {value:?}"))),
+        };
+
+        let original_offset = match property(OFFSET_PROPERTY)? {
+            SimpleValue::Ulong(offset) => *offset,
+            value => return Err(malformed(format!("{OFFSET_PROPERTY} is {value:?}"))),
+        };
+        let error = serde_json::from_str(&text(ERROR_PROPERTY)?)
+            .map_err(|e| malformed(format!("{ERROR_PROPERTY}: {e}")))?;
+        let dead_lettered_at = DateTime::parse_from_rfc3339(&text(TIMESTAMP_PROPERTY)?)
+            .map_err(|e| malformed(format!("{TIMESTAMP_PROPERTY}: {e}")))?
+            .with_timezone(&Utc);
+        let body = delivery
+            .message
+            .data()
+            .ok_or_else(|| malformed("no body".to_string()))?;
+        let (_, message) = Message::decode(body).map_err(|e| malformed(format!("{e:?}")))?;
+
+        Ok(Self {
+            offset,
+            stream: text(STREAM_PROPERTY)?,
+            original_offset,
+            error,
+            dead_lettered_at,
+            message,
+        })
+    }
+}
+
+pub(crate) struct DeadLetterProducer {
+    stream: String,
+    producer: Box<dyn TransportProducer>,
+}
+impl DeadLetterProducer {
+    pub fn new(stream: String, producer: Box<dyn TransportProducer>) -> Self {
+        Self { stream, producer }
+    }
+
+    pub async fn send(
+        &self,
+        stream: &str,
+        delivery: &TransportDelivery,
+        error: &PackerError,
+    ) -> DeadLetterResult<()> {
+        let pack = DeadLetter::pack(stream, delivery, error)?;
+        self.producer
+            .send(Box::new(|options| Ok(pack(options))), true)
+            .await?;
+        tracing::warn!(
+            stream,
+            offset = delivery.offset,
+            dead_letter_stream = self.stream,
+            "Dead-lettered message: {error}"
+        );
+        Ok(())
+    }
+}
+
+/// Reads a channel's dead-letter stream, to republish messages once what broke them is fixed
+pub struct DeadLetterQueue {
+    transport: Arc<dyn Transport>,
+    config: ChannelConfiguration,
+    stream: String,
+}
+impl DeadLetterQueue {
+    #[tracing::instrument(name = "mq.dead_letter.new")]
+    pub async fn new(config: &ChannelConfiguration) -> DeadLetterResult<Self> {
+        let transport = RabbitMqTransport::connect(config)
+            .await
+            .map_err(|e| DeadLetterError::CreateEnvironment(e.to_string()))?;
+
+        Self::with_transport(config, transport).await
+    }
+
+    #[tracing::instrument(name = "mq.dead_letter.with_transport", skip(transport))]
+    pub async fn with_transport(
+        config: &ChannelConfiguration,
+        transport: impl Transport,
+    ) -> DeadLetterResult<Self> {
+        let stream = config
+            .dead_letter_stream
+            .clone()
+            .ok_or(DeadLetterError::Disabled)?;
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        transport.ensure_stream(config, &stream, None).await?;
+
+        // Reading and re-publishing are one-off jobs, they shouldn't touch the channel's
+        // consumer offsets or producer sequences
+        let mut config = config.clone();
+        config.consumer_name = None;
+        config.single_active_consumer = false;
+        config.deduplicate = false;
+
+        Ok(Self {
+            transport,
+            config,
+            stream,
+        })
+    }
+
+    /// Up to `max` dead letters from offset `from`, fewer once the stream has been quiet for a
+    /// second
+    #[tracing::instrument(name = "mq.dead_letter.list", skip(self))]
+    pub async fn list(&self, from: u64, max: usize) -> DeadLetterResult<Vec<DeadLetter>> {
+        let mut config = self.config.clone();
+        config.start_position = StartPosition::Offset(from);
+        let mut consumer = self
+            .transport
+            .consumer(&config, &self.stream, None, None)
+            .await
+            .map_err(DeadLetterError::CreateConsumer)?;
+
+        let mut letters = vec![];
+        while letters.len() < max {
+            let delivery = match tokio::time::timeout(LIST_IDLE_TIMEOUT, consumer.next()).await {
+                Ok(Some(delivery)) => delivery.map_err(DeadLetterError::Receive)?,
+                Ok(None) | Err(_) => break,
+            };
+            // The first chunk a consumer gets can start before the offset asked for
+            if delivery.offset < from {
+                continue;
+            }
+            match DeadLetter::unpack(delivery) {
+                Ok(letter) => letters.push(letter),
+                // One bad letter shouldn't hide the ones after it
+                Err(e) => tracing::warn!(stream = self.stream, "Skipping dead letter: {e}"),
+            }
+        }
+        Ok(letters)
+    }
+
+    /// Publish the original message back to the stream it was read from
+    #[tracing::instrument(name = "mq.dead_letter.republish", skip(self, letter), fields(offset = letter.offset, stream = letter.stream))]
+    pub async fn republish(&self, letter: &DeadLetter) -> DeadLetterResult<()> {
+        let producer = self
+            .transport
+            .producer(&self.config, &letter.stream)
+            .await
+            .map_err(DeadLetterError::CreateProducer)?;
+        let message = letter.message.clone();
+        producer.send(Box::new(|_| Ok(message)), true).await?;
+        tracing::info!(
+            original_offset = letter.original_offset,
+            "Republished dead letter"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        channel::ChannelConfigurationBuilder,
+        client::MessageQueueClient,
+        pack::{JsonPacker, Packer},
+        payload::MessageQueuePayload,
+        server::MessageQueueServer,
+        transport::{InMemoryTransport, Pack},
+    };
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Job(u32);
+    impl MessageQueuePayload for Job {
+        type Discriminant = &'static str;
+
+        fn discriminant(&self) -> Self::Discriminant {
+            "job"
+        }
+    }
+
+    fn raw(body: &'static [u8]) -> Pack<'static> {
+        Box::new(move |_| {
+            Ok(Message::builder()
+                .body(body)
+                .properties()
+                .content_type(JsonPacker::CONTENT_TYPE)
+                .message_builder()
+                .build())
+        })
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_listed_and_republished() {
+        let config = ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("jobs")
+            .dead_letter_stream("jobs.dead")
+            .start_position(StartPosition::First)
+            .build()
+            .unwrap();
+        let transport = InMemoryTransport::new();
+        let mut server = MessageQueueServer::<Job, Job, JsonPacker>::with_transport(
+            "server".into(),
+            &config,
+            transport.clone(),
+        )
+        .await
+        .unwrap();
+        let client = MessageQueueClient::<Job, Job, JsonPacker>::with_transport(
+            "client".into(),
+            &config,
+            transport.clone(),
+        )
+        .await
+        .unwrap();
+        let calls = config.call_stream_name();
+        let producer = transport.producer(&config, &calls).await.unwrap();
+        let dead_letters = transport.producer(&config, "jobs.dead").await.unwrap();
+
+        // Not something a server wrote, listing steps over it
+        assert!(dead_letters.send(raw(b"malformed"), true).await.is_ok());
+        assert!(producer.send(raw(b"not json"), true).await.is_ok());
+        client.send(Job(1)).await.unwrap();
+        let jobs = server.recv().await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].payload, Job(1));
+
+        let queue = DeadLetterQueue::with_transport(&config, transport.clone())
+            .await
+            .unwrap();
+        let letters = queue.list(0, 1).await.unwrap();
+        let [letter] = letters.as_slice() else {
+            panic!("expected one dead letter, got {letters:?}");
+        };
+        assert_eq!(letter.offset, 1);
+        assert_eq!(letter.stream, calls);
+        assert_eq!(letter.original_offset, 0);
+        assert!(matches!(letter.error, PackerError::Deserialize(_)));
+
+        queue.republish(letter).await.unwrap();
+        let consumer = transport
+            .consumer(&config, &calls, None, None)
+            .await
+            .unwrap();
+        let republished = consumer.skip(2).next().await.unwrap().unwrap();
+        assert_eq!(republished.offset, 2);
+        assert_eq!(republished.message.data(), Some(&b"not json"[..]));
+    }
+}
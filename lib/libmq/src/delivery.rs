@@ -0,0 +1,26 @@
+use crate::meta::ManagerMeta;
+
+/// A decoded message along with where it was read from and the envelope it arrived in
+#[derive(Debug, Clone)]
+pub struct Delivery<T> {
+    /// Partition of the stream this was read from, always 0 when the stream isn't partitioned
+    pub partition: usize,
+    pub offset: u64,
+    pub meta: ManagerMeta,
+    pub payload: T,
+}
+impl<T> Delivery<T> {
+    pub fn new(offset: u64, meta: ManagerMeta, payload: T) -> Self {
+        Self {
+            partition: 0,
+            offset,
+            meta,
+            payload,
+        }
+    }
+
+    pub fn with_partition(mut self, partition: usize) -> Self {
+        self.partition = partition;
+        self
+    }
+}
@@ -1,10 +1,30 @@
+pub mod batch;
 pub mod channel;
+pub mod chunk;
 pub mod client;
+pub mod compression;
+pub mod confirm;
+pub mod connection;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+pub mod dead_letter;
+pub mod delivery;
 pub mod message;
 pub mod meta;
+mod metrics;
+pub mod offset;
 pub mod pack;
+mod partition;
 pub mod payload;
+mod producer;
+pub mod registry;
+pub mod retention;
+pub mod schema;
+pub mod security;
 pub mod server;
+mod stream;
+pub mod transport;
+pub mod uri;
 
 #[macro_export]
 macro_rules! nt_channel {
@@ -19,6 +39,20 @@ macro_rules! nt_channel {
                     libmq::client::MessageQueueClient::new(client_name, mq_config).await?,
                 ))
             }
+            pub async fn with_transport(
+                client_name: String,
+                mq_config: &libmq::channel::ChannelConfiguration,
+                transport: impl libmq::transport::Transport,
+            ) -> libmq::client::MessageQueueClientResult<Self> {
+                Ok(Self(
+                    libmq::client::MessageQueueClient::with_transport(
+                        client_name,
+                        mq_config,
+                        transport,
+                    )
+                    .await?,
+                ))
+            }
         }
         impl std::ops::Deref for $clientname {
             type Target = libmq::client::MessageQueueClient<$call, $resp, $packer>;
@@ -42,6 +76,20 @@ macro_rules! nt_channel {
                     libmq::server::MessageQueueServer::new(service_name, mq_config).await?,
                 ))
             }
+            pub async fn with_transport(
+                service_name: String,
+                mq_config: &libmq::channel::ChannelConfiguration,
+                transport: impl libmq::transport::Transport,
+            ) -> libmq::server::MessageQueueServerResult<Self> {
+                Ok(Self(
+                    libmq::server::MessageQueueServer::with_transport(
+                        service_name,
+                        mq_config,
+                        transport,
+                    )
+                    .await?,
+                ))
+            }
         }
         impl std::ops::Deref for $servname {
             type Target = libmq::server::MessageQueueServer<$call, $resp, $packer>;
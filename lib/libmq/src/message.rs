@@ -1,7 +1,14 @@
+use rabbitmq_stream_client::types::Message;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
 use std::fmt::Debug;
 
-use crate::meta::ManagerMeta;
+use crate::{
+    meta::{ManagerMeta, TraceContext},
+    pack::{PackOptions, Packer, PackerError, PackerResult},
+    payload::MessageQueuePayload,
+    schema::{self, SCHEMA_VERSION_PROPERTY},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(bound(deserialize = "TCall: DeserializeOwned, TResponse: DeserializeOwned"))]
@@ -40,3 +47,233 @@ impl<
         Self::new(meta, ManagerMessagePayload::Response(response))
     }
 }
+
+impl<TCall: MessageQueuePayload, TResponse: MessageQueuePayload> ManagerMessage<TCall, TResponse> {
+    /// Schema version of whichever payload this carries
+    fn schema_version(&self) -> u32 {
+        match &self.payload {
+            ManagerMessagePayload::Call(_) => TCall::SCHEMA_VERSION,
+            ManagerMessagePayload::Response(_) => TResponse::SCHEMA_VERSION,
+        }
+    }
+
+    fn trace_context(&self) -> Option<TraceContext> {
+        match &self.payload {
+            ManagerMessagePayload::Call(call) => call.trace_context(),
+            ManagerMessagePayload::Response(response) => response.trace_context(),
+        }
+    }
+
+    /// Pack with `P`, stamping the payload's schema version, the meta and the payload's trace
+    /// context on the message
+    pub fn pack<P: Packer>(mut self, options: &PackOptions) -> PackerResult<Message> {
+        self.meta.trace_context = self.trace_context().or(self.meta.trace_context.take());
+        let options = PackOptions {
+            schema_version: Some(self.schema_version()),
+            meta: Some(self.meta.clone()),
+            ..options.clone()
+        };
+        P::pack_with(self, &options)
+    }
+
+    /// Unpack with `P`, upgrading a payload of an older schema version to the current one.
+    /// Newer payloads are accepted for as long as they still decode.
+    pub fn unpack<P: Packer>(message: &Message) -> PackerResult<Self> {
+        P::validate(message)?;
+        let version = schema::version(message, SCHEMA_VERSION_PROPERTY)?;
+        let body = P::body(message)?;
+
+        let mut decoded = match P::de_message::<Self>(message, &body) {
+            Ok(decoded) if version >= decoded.schema_version() => decoded,
+            decoded => Self::upgrade::<P>(message, &body, version)?.map_or(decoded, Ok)?,
+        };
+        // The properties are the only place the trace context travels
+        decoded.meta.trace_context = ManagerMeta::trace_context(message)?;
+        Ok(decoded)
+    }
+
+    /// Decode the payload format-neutrally and run it through its upgrades, if it is older
+    fn upgrade<P: Packer>(
+        message: &Message,
+        body: &[u8],
+        version: u32,
+    ) -> PackerResult<Option<Self>> {
+        let Ok(raw) = P::de_message::<ManagerMessage<Value, Value>>(message, body) else {
+            return Ok(None);
+        };
+        let payload = match raw.payload {
+            ManagerMessagePayload::Call(value) if version < TCall::SCHEMA_VERSION => {
+                let value = TCall::upgrades().upgrade(value, version, TCall::SCHEMA_VERSION)?;
+                ManagerMessagePayload::Call(from_value(value)?)
+            }
+            ManagerMessagePayload::Response(value) if version < TResponse::SCHEMA_VERSION => {
+                let value =
+                    TResponse::upgrades().upgrade(value, version, TResponse::SCHEMA_VERSION)?;
+                ManagerMessagePayload::Response(from_value(value)?)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(Self::new(raw.meta, payload)))
+    }
+}
+
+fn from_value<T: DeserializeOwned>(value: Value) -> PackerResult<T> {
+    serde_json::from_value(value).map_err(|e| PackerError::Deserialize(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rabbitmq_stream_client::types::SimpleValue;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        pack::{JsonPacker, MessagePackPacker},
+        schema::{SchemaUpgrades, ENVELOPE_VERSION, ENVELOPE_VERSION_PROPERTY},
+    };
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Ping(String);
+    impl MessageQueuePayload for Ping {
+        type Discriminant = &'static str;
+
+        fn discriminant(&self) -> Self::Discriminant {
+            "ping"
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct NameV1 {
+        name: String,
+    }
+    impl MessageQueuePayload for NameV1 {
+        type Discriminant = &'static str;
+
+        fn discriminant(&self) -> Self::Discriminant {
+            "name"
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct NameV2 {
+        full_name: String,
+    }
+    impl MessageQueuePayload for NameV2 {
+        type Discriminant = &'static str;
+        const SCHEMA_VERSION: u32 = 2;
+
+        fn discriminant(&self) -> Self::Discriminant {
+            "name"
+        }
+
+        fn upgrades() -> SchemaUpgrades {
+            SchemaUpgrades::default().with(1, |mut value| {
+                let name = value["name"].take();
+                Ok(serde_json::json!({ "full_name": name }))
+            })
+        }
+    }
+
+    fn upgrades_with<P: Packer>() {
+        let old = ManagerMessage::<NameV1, NameV1>::new_call(
+            ManagerMeta::new("old"),
+            NameV1 { name: "ada".into() },
+        )
+        .pack::<P>(&PackOptions::default())
+        .unwrap();
+
+        let upgraded = ManagerMessage::<NameV2, NameV2>::unpack::<P>(&old).unwrap();
+        assert!(matches!(
+            upgraded.payload,
+            ManagerMessagePayload::Call(NameV2 { full_name }) if full_name == "ada"
+        ));
+        assert_eq!(upgraded.meta.origin, "old");
+    }
+
+    #[test]
+    fn upgrades_older_payloads() {
+        upgrades_with::<JsonPacker>();
+        upgrades_with::<MessagePackPacker>();
+    }
+
+    #[test]
+    fn newer_payloads_decode_while_they_still_can() {
+        let new =
+            ManagerMessage::<Ping, Ping>::new_call(ManagerMeta::new("new"), Ping("hi".into()))
+                .pack::<JsonPacker>(&PackOptions {
+                    schema_version: Some(7),
+                    ..PackOptions::default()
+                })
+                .unwrap();
+        assert!(ManagerMessage::<Ping, Ping>::unpack::<JsonPacker>(&new).is_ok());
+    }
+
+    #[test]
+    fn rejects_newer_envelopes() {
+        let packed =
+            ManagerMessage::<Ping, Ping>::new_call(ManagerMeta::new("a"), Ping("hi".into()))
+                .pack::<JsonPacker>(&PackOptions::default())
+                .unwrap();
+        let newer = Message::builder()
+            .body(packed.data().unwrap().to_vec())
+            .properties()
+            .content_type(JsonPacker::CONTENT_TYPE)
+            .message_builder()
+            .application_properties()
+            .insert(
+                ENVELOPE_VERSION_PROPERTY,
+                SimpleValue::Uint(ENVELOPE_VERSION + 1),
+            )
+            .message_builder()
+            .build();
+        assert!(matches!(
+            ManagerMessage::<Ping, Ping>::unpack::<JsonPacker>(&newer),
+            Err(PackerError::Validation(
+                crate::pack::PackerValidateError::UnsupportedVersion { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn meta_comes_from_the_body_not_the_properties() {
+        let meta = ManagerMeta::new("alice").with_ttl(Duration::from_secs(60));
+        let packed = ManagerMessage::<Ping, Ping>::new_call(meta.clone(), Ping("hi".into()))
+            .pack::<JsonPacker>(&PackOptions::default())
+            .unwrap();
+
+        // Same body, with properties claiming a different sender and no deadline
+        let forged = Message::builder()
+            .body(packed.data().unwrap().to_vec())
+            .properties()
+            .content_type(JsonPacker::CONTENT_TYPE)
+            .message_builder()
+            .application_properties()
+            .insert(
+                ENVELOPE_VERSION_PROPERTY,
+                SimpleValue::Uint(ENVELOPE_VERSION),
+            )
+            .insert(SCHEMA_VERSION_PROPERTY, SimpleValue::Uint(1))
+            .insert(
+                "x-request-id",
+                SimpleValue::String(Uuid::new_v4().to_string()),
+            )
+            .insert("x-origin", SimpleValue::String("mallory".into()))
+            .insert(
+                "traceparent",
+                SimpleValue::String("00-trace-span-01".into()),
+            )
+            .message_builder()
+            .build();
+
+        let unpacked = ManagerMessage::<Ping, Ping>::unpack::<JsonPacker>(&forged).unwrap();
+        assert_eq!(unpacked.meta.request_id, meta.request_id);
+        assert_eq!(unpacked.meta.origin, "alice");
+        assert_eq!(unpacked.meta.deadline, meta.deadline);
+        assert_eq!(
+            unpacked.meta.trace_context.map(|trace| trace.traceparent),
+            Some("00-trace-span-01".to_string())
+        );
+    }
+}
@@ -1,7 +1,31 @@
+use std::time::Duration;
+
 use chrono::Utc;
+use rabbitmq_stream_client::types::{Message, SimpleValue};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::pack::{PackerResult, PackerValidateError};
+
+const REQUEST_ID_PROPERTY: &str = "x-request-id";
+const PARENT_ID_PROPERTY: &str = "x-parent-id";
+const ORIGIN_PROPERTY: &str = "x-origin";
+const CREATED_AT_PROPERTY: &str = "x-created-at";
+const DEADLINE_PROPERTY: &str = "x-deadline";
+const TRACEPARENT_PROPERTY: &str = "traceparent";
+const TRACESTATE_PROPERTY: &str = "tracestate";
+/// Everything `ManagerMeta::properties` may write
+#[cfg(feature = "crypto")]
+pub(crate) const PROPERTIES: &[&str] = &[
+    REQUEST_ID_PROPERTY,
+    PARENT_ID_PROPERTY,
+    ORIGIN_PROPERTY,
+    CREATED_AT_PROPERTY,
+    DEADLINE_PROPERTY,
+    TRACEPARENT_PROPERTY,
+    TRACESTATE_PROPERTY,
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct ManagerMeta {
@@ -9,6 +33,13 @@ pub struct ManagerMeta {
     pub parent_id: Option<Uuid>,
     pub origin: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Past this point nobody is waiting for an answer, and servers drop the call unanswered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only ever carried in the message's application properties, taken from the payload as
+    /// it is packed
+    #[serde(skip)]
+    pub trace_context: Option<TraceContext>,
 }
 impl ManagerMeta {
     pub fn new<S: ToString>(origin: S) -> Self {
@@ -26,6 +57,95 @@ impl ManagerMeta {
             origin: origin.to_string(),
             parent_id: Some(self.request_id),
             created_at: Utc::now(),
+            deadline: None,
+            trace_context: None,
+        }
+    }
+
+    /// Give up on the message `ttl` after it was created
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.deadline = chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| self.created_at.checked_add_signed(ttl));
+        self
+    }
+
+    /// Whether the deadline has passed. Compared against this host's clock, so clocks that
+    /// drift apart move it by as much.
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| deadline <= Utc::now())
+    }
+
+    /// Application properties that let anything reading the stream see who sent a message, and
+    /// what it answers, without decoding the body
+    pub(crate) fn properties(&self) -> Vec<(&'static str, SimpleValue)> {
+        let mut properties = vec![
+            (
+                REQUEST_ID_PROPERTY,
+                SimpleValue::String(self.request_id.to_string()),
+            ),
+            (ORIGIN_PROPERTY, self.origin.as_str().into()),
+            (
+                CREATED_AT_PROPERTY,
+                SimpleValue::String(self.created_at.to_rfc3339()),
+            ),
+        ];
+        if let Some(deadline) = self.deadline {
+            properties.push((
+                DEADLINE_PROPERTY,
+                SimpleValue::String(deadline.to_rfc3339()),
+            ));
+        }
+        if let Some(parent_id) = self.parent_id {
+            properties.push((
+                PARENT_ID_PROPERTY,
+                SimpleValue::String(parent_id.to_string()),
+            ));
+        }
+        if let Some(trace_context) = &self.trace_context {
+            properties.push((
+                TRACEPARENT_PROPERTY,
+                trace_context.traceparent.as_str().into(),
+            ));
+            if let Some(tracestate) = &trace_context.tracestate {
+                properties.push((TRACESTATE_PROPERTY, tracestate.as_str().into()));
+            }
+        }
+        properties
+    }
+
+    /// The trace context written by `properties`. The rest of the meta is read from the body,
+    /// where signing and encryption packers cover it.
+    pub(crate) fn trace_context(message: &Message) -> PackerResult<Option<TraceContext>> {
+        let Some(traceparent) = property(message, TRACEPARENT_PROPERTY)? else {
+            return Ok(None);
+        };
+        Ok(Some(TraceContext {
+            traceparent: traceparent.to_string(),
+            tracestate: property(message, TRACESTATE_PROPERTY)?.map(str::to_string),
+        }))
+    }
+}
+
+/// W3C trace context of the transaction a message belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub traceparent: String,
+    pub tracestate: Option<String>,
+}
+
+fn property<'a>(message: &'a Message, property_name: &str) -> PackerResult<Option<&'a str>> {
+    match message
+        .application_properties()
+        .and_then(|properties| properties.get(property_name))
+    {
+        None => Ok(None),
+        Some(SimpleValue::String(value)) => Ok(Some(value.as_str())),
+        Some(value) => Err(PackerValidateError::IncorrectPropertyValue {
+            property_name: property_name.to_string(),
+            expected_value: "string".to_string(),
+            actual_value: format!("{value:?}"),
         }
+        .into()),
     }
 }
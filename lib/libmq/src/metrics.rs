@@ -0,0 +1,129 @@
+use std::sync::OnceLock;
+
+use opentelemetry::{global, metrics::Counter, KeyValue};
+
+// Recorded through the global meter provider, so they go nowhere unless the application
+// installs one
+
+static EXPIRED_CALLS: OnceLock<Counter<u64>> = OnceLock::new();
+
+pub(crate) fn expired_call(stream: &str) {
+    EXPIRED_CALLS
+        .get_or_init(|| {
+            global::meter("libmq")
+                .u64_counter("mq.server.expired_calls")
+                .with_description("Calls dropped unanswered because their deadline had passed")
+                .build()
+        })
+        .add(1, &[KeyValue::new("stream", stream.to_string())]);
+}
+
+static REJECTED_PUBLISHES: OnceLock<Counter<u64>> = OnceLock::new();
+
+fn rejected_publishes() -> &'static Counter<u64> {
+    REJECTED_PUBLISHES.get_or_init(|| {
+        global::meter("libmq")
+            .u64_counter("mq.producer.rejected_publishes")
+            .with_description(
+                "Publishes the broker rejected, or that were refused for a full confirm backlog",
+            )
+            .build()
+    })
+}
+
+pub(crate) fn rejected_publish(stream: &str, code: u16) {
+    rejected_publishes().add(
+        1,
+        &[
+            KeyValue::new("stream", stream.to_string()),
+            KeyValue::new("code", i64::from(code)),
+        ],
+    );
+}
+
+pub(crate) fn backlog_full(stream: &str) {
+    rejected_publishes().add(
+        1,
+        &[
+            KeyValue::new("stream", stream.to_string()),
+            KeyValue::new("reason", "backlog_full"),
+        ],
+    );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::{Arc, Weak};
+
+    use opentelemetry_sdk::{
+        metrics::{
+            data::{ResourceMetrics, Sum},
+            reader::MetricReader,
+            InstrumentKind, ManualReader, MetricResult, Pipeline, SdkMeterProvider, Temporality,
+        },
+        Resource,
+    };
+
+    use super::*;
+
+    // The provider owns the reader it is built with, this keeps a handle to read it through
+    #[derive(Debug, Clone)]
+    struct SharedReader(Arc<ManualReader>);
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn collect(&self, metrics: &mut ResourceMetrics) -> MetricResult<()> {
+            self.0.collect(metrics)
+        }
+
+        fn force_flush(&self) -> MetricResult<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> MetricResult<()> {
+            self.0.shutdown()
+        }
+
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    static READER: OnceLock<SharedReader> = OnceLock::new();
+
+    /// What counter `name` has recorded for `stream`. Counters stick to the provider that was
+    /// global when first used, so tests call this before recording anything, and use streams of
+    /// their own.
+    pub(crate) fn recorded(name: &str, stream: &str) -> u64 {
+        let reader = READER.get_or_init(|| {
+            let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+            global::set_meter_provider(
+                SdkMeterProvider::builder()
+                    .with_reader(reader.clone())
+                    .build(),
+            );
+            reader
+        });
+        let mut metrics = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: vec![],
+        };
+        reader.collect(&mut metrics).unwrap();
+        metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| &scope.metrics)
+            .filter(|metric| metric.name == name)
+            .filter_map(|metric| metric.data.as_any().downcast_ref::<Sum<u64>>())
+            .flat_map(|sum| &sum.data_points)
+            .filter(|point| {
+                point.attributes.iter().any(|attribute| {
+                    attribute.key.as_str() == "stream" && attribute.value.as_str() == stream
+                })
+            })
+            .map(|point| point.value)
+            .sum()
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use liberror::AnyError;
+use rabbitmq_stream_client::types::OffsetSpecification;
+use tokio::{sync::oneshot, time::Interval};
+
+use crate::transport::TransportConsumer;
+
+/// Where a consumer starts reading when it has no stored offset to resume from
+#[derive(Debug, Clone, Default)]
+pub enum StartPosition {
+    First,
+    /// The last chunk written to the stream
+    Last,
+    /// Only messages published after subscribing
+    #[default]
+    Next,
+    Offset(u64),
+    Timestamp(chrono::DateTime<chrono::Utc>),
+}
+impl From<&StartPosition> for OffsetSpecification {
+    fn from(value: &StartPosition) -> Self {
+        match value {
+            StartPosition::First => OffsetSpecification::First,
+            StartPosition::Last => OffsetSpecification::Last,
+            StartPosition::Next => OffsetSpecification::Next,
+            StartPosition::Offset(offset) => OffsetSpecification::Offset(*offset),
+            StartPosition::Timestamp(timestamp) => {
+                OffsetSpecification::Timestamp(timestamp.timestamp_millis())
+            }
+        }
+    }
+}
+
+/// When a named consumer stores its progress on the broker
+#[derive(Debug, Clone, Default)]
+pub enum CommitPolicy {
+    #[default]
+    EachMessage,
+    EveryMessages(u64),
+    Every(Duration),
+}
+
+pub(crate) enum OffsetCommand {
+    Processed {
+        partition: usize,
+        offset: u64,
+        count: u64,
+    },
+    Commit(oneshot::Sender<Result<(), AnyError>>),
+}
+
+// Without a consumer name there is nowhere to store offsets, so nothing is committed
+pub(crate) struct OffsetTracker {
+    policy: CommitPolicy,
+    enabled: bool,
+    processed: Option<u64>,
+    committed: Option<u64>,
+    uncommitted: u64,
+}
+impl OffsetTracker {
+    pub fn new(policy: CommitPolicy, consumer_name: Option<&str>) -> Self {
+        Self {
+            policy,
+            enabled: consumer_name.is_some(),
+            processed: None,
+            committed: None,
+            uncommitted: 0,
+        }
+    }
+
+    pub fn interval(&self) -> Option<Interval> {
+        match self.policy {
+            CommitPolicy::Every(period) if self.enabled => Some(tokio::time::interval(period)),
+            _ => None,
+        }
+    }
+
+    pub fn processed(&mut self, offset: u64, count: u64) {
+        if self.processed.is_some_and(|processed| processed >= offset) {
+            return;
+        }
+        self.processed = Some(offset);
+        self.uncommitted += count;
+    }
+
+    fn pending(&self) -> Option<u64> {
+        self.processed
+            .filter(|_| self.enabled)
+            .filter(|processed| self.committed != Some(*processed))
+    }
+
+    fn due(&self) -> Option<u64> {
+        match self.policy {
+            CommitPolicy::EachMessage => self.pending(),
+            CommitPolicy::EveryMessages(count) if self.uncommitted >= count => self.pending(),
+            _ => None,
+        }
+    }
+
+    pub async fn store(
+        &mut self,
+        consumer: &dyn TransportConsumer,
+        force: bool,
+    ) -> Result<(), AnyError> {
+        let offset = if force { self.pending() } else { self.due() };
+        let Some(offset) = offset else {
+            return Ok(());
+        };
+
+        consumer.store_offset(offset).await?;
+        tracing::trace!(offset, "Stored consumer offset");
+        self.committed = Some(offset);
+        self.uncommitted = 0;
+        Ok(())
+    }
+}
+
+pub(crate) async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
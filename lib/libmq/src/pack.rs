@@ -1,8 +1,16 @@
+use std::borrow::Cow;
+
 use liberror::AnyError;
-use rabbitmq_stream_client::types::Message;
+use rabbitmq_stream_client::types::{Message, SimpleValue};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::{
+    compression,
+    meta::ManagerMeta,
+    schema::{self, ENVELOPE_VERSION, ENVELOPE_VERSION_PROPERTY},
+};
+
 #[derive(Debug, Clone, Error, Serialize, Deserialize, valuable::Valuable)]
 #[serde(tag = "$type", content = "reason")]
 pub enum PackerValidateError {
@@ -21,6 +29,19 @@ pub enum PackerValidateError {
         expected_value: String,
         actual_value: String,
     },
+    #[error("Property named \"{property_name}\" is version {actual}, newer than {supported}")]
+    #[serde(rename = "dev.thmsn.mq.packer.validate.unsupported_version")]
+    UnsupportedVersion {
+        property_name: String,
+        supported: u32,
+        actual: u32,
+    },
+    #[error("No key with id \"{key_id}\"")]
+    #[serde(rename = "dev.thmsn.mq.packer.validate.unknown_key")]
+    UnknownKey { key_id: String },
+    #[error("Signature by key \"{key_id}\" doesn't match: {reason}")]
+    #[serde(rename = "dev.thmsn.mq.packer.validate.invalid_signature")]
+    InvalidSignature { key_id: String, reason: String },
 }
 
 #[derive(Debug, Clone, Error, Serialize, Deserialize, valuable::Valuable)]
@@ -40,46 +61,173 @@ pub enum PackerError {
     #[error("Unable to parse message, no body present")]
     #[serde(rename = "dev.thmsn.mq.packer.missing_body")]
     MissingBody,
+
+    #[error("Failed to compress message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.packer.compress")]
+    Compress(AnyError),
+    #[error("Failed to decompress message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.packer.decompress")]
+    Decompress(AnyError),
+    #[error("Message decompresses to more than {limit} bytes")]
+    #[serde(rename = "dev.thmsn.mq.packer.too_large")]
+    TooLarge { limit: usize },
+
+    #[error("Failed to encrypt message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.packer.encrypt")]
+    Encrypt(String),
+    #[error("Failed to decrypt message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.packer.decrypt")]
+    Decrypt(String),
+
+    #[error("Content type \"{0}\" isn't supported by this packer")]
+    #[serde(rename = "dev.thmsn.mq.packer.unsupported_content_type")]
+    UnsupportedContentType(String),
+
+    #[error("Failed to upgrade payload from schema version {from} to {to}: {reason}")]
+    #[serde(rename = "dev.thmsn.mq.packer.upgrade")]
+    Upgrade { from: u32, to: u32, reason: String },
 }
 pub type PackerResult<T> = Result<T, PackerError>;
 
-pub trait Packer: std::fmt::Debug {
+/// Details stamped onto a message, outside its body, as it is packed
+#[derive(Debug, Clone, Default)]
+pub struct PackOptions {
+    /// Set by deduplicating producers, the broker drops a message whose id it has already seen
+    pub publishing_id: Option<u64>,
+    /// Schema version of the payload being packed
+    pub schema_version: Option<u32>,
+    /// Copied into application properties, so the message can be routed and traced without
+    /// decoding its body
+    pub meta: Option<ManagerMeta>,
+    /// Picks the encoding, for packers that support more than one
+    pub content_type: Option<String>,
+}
+
+/// A serialized payload as it goes on the wire
+#[derive(Debug, Clone, Default)]
+pub struct Encoded {
+    pub bytes: Vec<u8>,
+    /// Recorded as the message's content encoding
+    pub content_encoding: Option<&'static str>,
+    /// Added to the message's application properties
+    pub properties: Vec<(&'static str, SimpleValue)>,
+}
+impl From<Vec<u8>> for Encoded {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            ..Self::default()
+        }
+    }
+}
+
+pub trait Packer: std::fmt::Debug + Send + Sync + Unpin + 'static {
     const CONTENT_TYPE: &'static str;
+    /// Whether `encode` encrypts the body
+    const ENCRYPTS: bool = false;
+    /// Largest body `decode` decompresses to, a message inflating past it is rejected
+    const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
 
     fn ser<Payload: Serialize>(payload: &Payload) -> PackerResult<Vec<u8>>;
     fn de<Payload: DeserializeOwned>(bytes: &[u8]) -> PackerResult<Payload>;
 
+    /// Whether this packer can pack and unpack messages of `content_type`
+    fn supports(content_type: &str) -> bool {
+        content_type == Self::CONTENT_TYPE
+    }
+
+    /// Deserialize the decoded body of `message`, for packers that need to know more than the
+    /// bytes to do so
+    fn de_message<Payload: DeserializeOwned>(
+        _message: &Message,
+        bytes: &[u8],
+    ) -> PackerResult<Payload> {
+        Self::de(bytes)
+    }
+
     fn pack<Payload: Serialize>(payload: Payload) -> PackerResult<Message> {
-        let bytes = Self::ser(&payload)?;
-        Ok(Message::builder()
-            .body(bytes)
+        Self::pack_with(payload, &PackOptions::default())
+    }
+
+    /// Last step before the serialized payload goes on the message, such as compressing it.
+    /// `properties` are the application properties the message goes out with.
+    fn encode(
+        bytes: Vec<u8>,
+        _properties: &[(&'static str, SimpleValue)],
+    ) -> PackerResult<Encoded> {
+        Ok(bytes.into())
+    }
+
+    /// Undo `encode`, going by what it recorded on the message
+    fn decode<'a>(message: &Message, bytes: Cow<'a, [u8]>) -> PackerResult<Cow<'a, [u8]>> {
+        let content_encoding = message
             .properties()
-            .content_type(Self::CONTENT_TYPE)
-            .message_builder()
-            .build())
+            .and_then(|properties| properties.content_encoding.as_ref());
+        match content_encoding {
+            Some(content_encoding) => {
+                let limit = Self::MAX_DECOMPRESSED_SIZE;
+                compression::decompress(content_encoding.as_str(), &bytes, limit).map(Cow::Owned)
+            }
+            None => Ok(bytes),
+        }
+    }
+
+    /// The message's body, decoded and ready to deserialize
+    fn body(message: &Message) -> PackerResult<Cow<'_, [u8]>> {
+        let body = message.data().ok_or(PackerError::MissingBody)?;
+        Self::decode(message, Cow::Borrowed(body))
+    }
+
+    fn pack_with<Payload: Serialize>(
+        payload: Payload,
+        options: &PackOptions,
+    ) -> PackerResult<Message> {
+        let mut properties = vec![(
+            ENVELOPE_VERSION_PROPERTY,
+            SimpleValue::Uint(ENVELOPE_VERSION),
+        )];
+        if let Some(version) = options.schema_version {
+            properties.push((schema::SCHEMA_VERSION_PROPERTY, SimpleValue::Uint(version)));
+        }
+        if let Some(meta) = &options.meta {
+            properties.extend(meta.properties());
+        }
+
+        let encoded = Self::encode(Self::ser(&payload)?, &properties)?;
+        let builder = Message::builder().body(encoded.bytes).properties();
+        let builder = match encoded.content_encoding {
+            Some(content_encoding) => builder.content_encoding(content_encoding),
+            None => builder,
+        }
+        .content_type(Self::CONTENT_TYPE)
+        .message_builder();
+
+        properties.extend(encoded.properties);
+        let builder = properties
+            .into_iter()
+            .fold(
+                builder.application_properties(),
+                |builder, (name, value)| builder.insert(name, value),
+            )
+            .message_builder();
+        let builder = match options.publishing_id {
+            Some(publishing_id) => builder.publising_id(publishing_id),
+            None => builder,
+        };
+        Ok(builder.build())
     }
 
     fn unpack<Payload: DeserializeOwned>(message: &Message) -> PackerResult<Payload> {
         Self::validate(message)?;
 
-        let body = message.data().ok_or(PackerError::MissingBody)?;
-        let payload = Self::de(body)?;
+        let body = Self::body(message)?;
+        let payload = Self::de_message(message, &body)?;
         Ok(payload)
     }
 
     fn validate(message: &Message) -> PackerResult<()> {
-        let properties = message
-            .properties()
-            .ok_or(PackerValidateError::NoProperties)?;
-
-        let content_type =
-            properties
-                .content_type
-                .as_ref()
-                .ok_or(PackerValidateError::MissingProperty {
-                    property_name: "content_type".into(),
-                })?;
-        if Self::CONTENT_TYPE != content_type.as_str() {
+        let content_type = content_type(message)?;
+        if Self::CONTENT_TYPE != content_type {
             return Err(PackerValidateError::IncorrectPropertyValue {
                 property_name: "content_type".into(),
                 expected_value: Self::CONTENT_TYPE.to_string(),
@@ -88,10 +236,36 @@ pub trait Packer: std::fmt::Debug {
             .into());
         }
 
+        // Older envelopes are still read as they are, there's no telling what a newer one holds
+        let envelope_version = schema::version(message, ENVELOPE_VERSION_PROPERTY)?;
+        if envelope_version > ENVELOPE_VERSION {
+            return Err(PackerValidateError::UnsupportedVersion {
+                property_name: ENVELOPE_VERSION_PROPERTY.into(),
+                supported: ENVELOPE_VERSION,
+                actual: envelope_version,
+            }
+            .into());
+        }
+
         Ok(())
     }
 }
 
+/// The content type the message was packed with
+pub(crate) fn content_type(message: &Message) -> PackerResult<&str> {
+    let properties = message
+        .properties()
+        .ok_or(PackerValidateError::NoProperties)?;
+    let content_type =
+        properties
+            .content_type
+            .as_ref()
+            .ok_or(PackerValidateError::MissingProperty {
+                property_name: "content_type".into(),
+            })?;
+    Ok(content_type.as_str())
+}
+
 #[derive(Debug)]
 pub struct MessagePackPacker;
 
@@ -121,3 +295,137 @@ impl Packer for JsonPacker {
         serde_json::from_slice(bytes).map_err(|e| PackerError::Deserialize(e.into()))
     }
 }
+
+#[cfg(feature = "cbor")]
+#[derive(Debug)]
+pub struct CborPacker;
+
+#[cfg(feature = "cbor")]
+impl Packer for CborPacker {
+    const CONTENT_TYPE: &'static str = "application/cbor";
+
+    fn ser<Payload: Serialize>(payload: &Payload) -> PackerResult<Vec<u8>> {
+        let mut bytes = vec![];
+        ciborium::into_writer(payload, &mut bytes).map_err(|e| PackerError::Serialize(e.into()))?;
+        Ok(bytes)
+    }
+
+    fn de<Payload: DeserializeOwned>(bytes: &[u8]) -> PackerResult<Payload> {
+        ciborium::from_reader(bytes).map_err(|e| PackerError::Deserialize(e.into()))
+    }
+}
+
+/// The protobuf message a `ProtobufPacker` encodes payloads as, usually looked up in the file
+/// descriptor set written by `prost-build`. Payloads go through the protobuf JSON mapping on
+/// their way in and out, so the fields' JSON names have to match the payload's serde names.
+#[cfg(feature = "protobuf")]
+pub trait ProtobufSchema: std::fmt::Debug + Send + Sync + Unpin + 'static {
+    fn descriptor() -> prost_reflect::MessageDescriptor;
+}
+
+#[cfg(feature = "protobuf")]
+#[derive(Debug)]
+pub struct ProtobufPacker<S: ProtobufSchema>(std::marker::PhantomData<S>);
+
+#[cfg(feature = "protobuf")]
+impl<S: ProtobufSchema> Packer for ProtobufPacker<S> {
+    const CONTENT_TYPE: &'static str = "application/protobuf";
+
+    fn ser<Payload: Serialize>(payload: &Payload) -> PackerResult<Vec<u8>> {
+        use prost::Message;
+
+        let value = serde_json::to_value(payload).map_err(|e| PackerError::Serialize(e.into()))?;
+        let message = prost_reflect::DynamicMessage::deserialize(S::descriptor(), value)
+            .map_err(|e| PackerError::Serialize(e.into()))?;
+        Ok(message.encode_to_vec())
+    }
+
+    fn de<Payload: DeserializeOwned>(bytes: &[u8]) -> PackerResult<Payload> {
+        let message = prost_reflect::DynamicMessage::decode(S::descriptor(), bytes)
+            .map_err(|e| PackerError::Deserialize(e.into()))?;
+        // Serde wants every field present, where protobuf leaves out the defaults
+        let options = prost_reflect::SerializeOptions::new().skip_default_fields(false);
+        let value = message
+            .serialize_with_options(serde_json::value::Serializer, &options)
+            .map_err(|e| PackerError::Deserialize(e.into()))?;
+        serde_json::from_value(value).map_err(|e| PackerError::Deserialize(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point {
+        x: i32,
+        label: String,
+    }
+
+    fn round_trips<P: Packer>() {
+        let point = Point {
+            x: 3,
+            label: "three".into(),
+        };
+        let message = P::pack(&point).unwrap();
+        assert_eq!(content_type(&message).unwrap(), P::CONTENT_TYPE);
+        assert_eq!(P::unpack::<Point>(&message).unwrap(), point);
+    }
+
+    #[test]
+    fn json_and_message_pack_round_trip() {
+        round_trips::<JsonPacker>();
+        round_trips::<MessagePackPacker>();
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips() {
+        round_trips::<CborPacker>();
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn protobuf_round_trips() {
+        use prost_reflect::{
+            prost_types::{
+                field_descriptor_proto::{Label, Type},
+                DescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+            },
+            DescriptorPool, MessageDescriptor,
+        };
+
+        #[derive(Debug)]
+        struct PointSchema;
+        impl ProtobufSchema for PointSchema {
+            fn descriptor() -> MessageDescriptor {
+                let field = |name: &str, number, r#type: Type| FieldDescriptorProto {
+                    name: Some(name.into()),
+                    json_name: Some(name.into()),
+                    number: Some(number),
+                    label: Some(Label::Optional.into()),
+                    r#type: Some(r#type.into()),
+                    ..Default::default()
+                };
+                let file = FileDescriptorProto {
+                    name: Some("point.proto".into()),
+                    package: Some("test".into()),
+                    syntax: Some("proto3".into()),
+                    message_type: vec![DescriptorProto {
+                        name: Some("Point".into()),
+                        field: vec![field("x", 1, Type::Int32), field("label", 2, Type::String)],
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                };
+                let mut pool = DescriptorPool::new();
+                pool.add_file_descriptor_proto(file).unwrap();
+                pool.get_message_by_name("test.Point").unwrap()
+            }
+        }
+
+        round_trips::<ProtobufPacker<PointSchema>>();
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    io::Cursor,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{stream::SelectAll, Stream, StreamExt};
+use liberror::AnyError;
+use tokio::time::Interval;
+
+use crate::{
+    channel::ChannelConfiguration,
+    offset::OffsetTracker,
+    transport::{Transport, TransportConsumer, TransportDelivery},
+};
+
+/// Same seed the RabbitMQ clients use for hash routing, so we agree with them on partitions
+const MURMUR_SEED: u32 = 104729;
+
+/// The partition a routing key is published to
+pub(crate) fn route(routing_key: &str, partitions: usize) -> usize {
+    if partitions <= 1 {
+        return 0;
+    }
+    let hash = murmur3::murmur3_32(&mut Cursor::new(routing_key), MURMUR_SEED).unwrap_or_default();
+    hash as usize % partitions
+}
+
+struct Partition {
+    index: usize,
+    consumer: Box<dyn TransportConsumer>,
+    closed: bool,
+}
+impl Partition {
+    fn new(index: usize, consumer: Box<dyn TransportConsumer>) -> Self {
+        Self {
+            index,
+            consumer,
+            closed: false,
+        }
+    }
+}
+impl Stream for Partition {
+    type Item = (usize, PartitionEvent);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+
+        let index = self.index;
+        match self.consumer.poll_next_unpin(cx) {
+            Poll::Ready(Some(delivery)) => {
+                Poll::Ready(Some((index, PartitionEvent::Delivery(delivery))))
+            }
+            // Say so once before dropping out of the select, so the partition can be rebuilt
+            Poll::Ready(None) => {
+                self.closed = true;
+                Poll::Ready(Some((index, PartitionEvent::Closed)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub(crate) enum PartitionEvent {
+    Delivery(Result<TransportDelivery, AnyError>),
+    /// The partition's consumer has stopped, most likely because its connection went away
+    Closed,
+}
+
+/// One consumer per partition of a stream, read as a single stream of deliveries tagged with the
+/// partition they came from. A stream that isn't partitioned is simply a single partition.
+pub(crate) struct PartitionedConsumer {
+    transport: Arc<dyn Transport>,
+    config: ChannelConfiguration,
+    streams: Vec<String>,
+    super_stream: Option<String>,
+    partitions: SelectAll<Partition>,
+    trackers: Vec<OffsetTracker>,
+    /// Per partition, the last offset read, where a rebuilt consumer picks up from
+    received: Vec<Option<u64>>,
+}
+impl PartitionedConsumer {
+    /// Subscribe to each of `streams`, the partitions of `super_stream` if there is one
+    pub async fn build(
+        transport: &Arc<dyn Transport>,
+        config: &ChannelConfiguration,
+        streams: Vec<String>,
+        super_stream: Option<String>,
+    ) -> Result<Self, AnyError> {
+        let mut partitions = vec![];
+        let mut trackers = vec![];
+        for (index, stream) in streams.iter().enumerate() {
+            let consumer = transport
+                .consumer(config, stream, super_stream.as_deref(), None)
+                .await?;
+            partitions.push(Partition::new(index, consumer));
+            trackers.push(OffsetTracker::new(
+                config.commit_policy.clone(),
+                config.consumer_name.as_deref(),
+            ));
+        }
+
+        Ok(Self {
+            transport: transport.clone(),
+            config: config.clone(),
+            received: vec![None; streams.len()],
+            streams,
+            super_stream,
+            partitions: futures::stream::select_all(partitions),
+            trackers,
+        })
+    }
+
+    /// Name of the stream a partition reads
+    pub fn stream(&self, partition: usize) -> &str {
+        &self.streams[partition]
+    }
+
+    /// Timer driving `CommitPolicy::Every`, shared by every partition
+    pub fn interval(&self) -> Option<Interval> {
+        self.trackers.first().and_then(OffsetTracker::interval)
+    }
+
+    /// Record progress on one partition, storing it if the commit policy says it's time
+    pub async fn processed(
+        &mut self,
+        partition: usize,
+        offset: u64,
+        count: u64,
+    ) -> Result<(), AnyError> {
+        let Some(tracker) = self.trackers.get_mut(partition) else {
+            return Ok(());
+        };
+        tracker.processed(offset, count);
+
+        // A partition being rebuilt catches up on its next store
+        match self
+            .partitions
+            .iter()
+            .find(|candidate| candidate.index == partition)
+        {
+            Some(partition) => tracker.store(partition.consumer.as_ref(), false).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Store progress on every partition, regardless of the commit policy
+    pub async fn store(&mut self) -> Result<(), AnyError> {
+        let mut result = Ok(());
+        for partition in self.partitions.iter() {
+            let tracker = &mut self.trackers[partition.index];
+            if let Err(e) = tracker.store(partition.consumer.as_ref(), true).await {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Rebuild a partition's consumer after it closed, resuming just after the last offset read
+    pub async fn recover(&mut self, partition: usize) -> Result<(), AnyError> {
+        let stream = &self.streams[partition];
+        let super_stream = self.super_stream.as_deref();
+        let resume = self.received[partition].map(|offset| offset + 1);
+        tracing::warn!(stream, ?resume, "Consumer closed, recovering");
+
+        let consumer = self
+            .transport
+            .recover_consumer(&self.config, stream, super_stream, resume)
+            .await?;
+        self.partitions.push(Partition::new(partition, consumer));
+        Ok(())
+    }
+}
+impl Stream for PartitionedConsumer {
+    type Item = (usize, PartitionEvent);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let event = self.partitions.poll_next_unpin(cx);
+        if let Poll::Ready(Some((partition, PartitionEvent::Delivery(Ok(delivery))))) = &event {
+            self.received[*partition] = Some(delivery.offset);
+        }
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{
+        channel::ChannelConfigurationBuilder, client::MessageQueueClient, offset::StartPosition,
+        pack::JsonPacker, payload::MessageQueuePayload, server::MessageQueueServer,
+        transport::InMemoryTransport,
+    };
+
+    const KEYS: [(&str, usize, usize); 7] = [
+        ("alice", 1, 3),
+        ("bob", 2, 0),
+        ("carol", 2, 1),
+        ("dave", 2, 2),
+        ("mallory", 0, 0),
+        ("peggy", 0, 3),
+        ("walter", 0, 2),
+    ];
+
+    #[test]
+    fn routing_is_pinned() {
+        // Published partitions are part of the wire contract, these mustn't change
+        for (key, of_three, of_four) in KEYS {
+            assert_eq!(route(key, 3), of_three, "{key} of 3");
+            assert_eq!(route(key, 4), of_four, "{key} of 4");
+            assert_eq!(route(key, 1), 0);
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Keyed(String);
+    impl MessageQueuePayload for Keyed {
+        type Discriminant = &'static str;
+
+        fn discriminant(&self) -> Self::Discriminant {
+            "keyed"
+        }
+
+        fn routing_key(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_arrive_on_their_partition() {
+        let config = ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("partitioned")
+            .call_partitions(3)
+            .start_position(StartPosition::First)
+            .build()
+            .unwrap();
+        let transport = InMemoryTransport::new();
+        let mut server = MessageQueueServer::<Keyed, Keyed, JsonPacker>::with_transport(
+            "server".into(),
+            &config,
+            transport.clone(),
+        )
+        .await
+        .unwrap();
+        let client = MessageQueueClient::<Keyed, Keyed, JsonPacker>::with_transport(
+            "client".into(),
+            &config,
+            transport,
+        )
+        .await
+        .unwrap();
+
+        for (key, _, _) in KEYS {
+            client.send(Keyed(key.to_string())).await.unwrap();
+        }
+        let mut received = vec![];
+        while received.len() < KEYS.len() {
+            received.extend(server.recv().await.unwrap());
+        }
+        for (key, partition, _) in KEYS {
+            let call = received.iter().find(|call| call.payload.0 == key).unwrap();
+            assert_eq!(call.partition, partition, "{key}");
+        }
+    }
+}
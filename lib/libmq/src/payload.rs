@@ -1,8 +1,163 @@
-use serde::{de::DeserializeOwned, Serialize};
-use std::fmt::Debug;
+use serde::{
+    de::{self, DeserializeOwned, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt::{self, Debug};
 
-pub trait MessageQueuePayload: Debug + Clone + Serialize + DeserializeOwned {
+use crate::{meta::TraceContext, schema::SchemaUpgrades};
+
+pub trait MessageQueuePayload:
+    Debug + Clone + Serialize + DeserializeOwned + Send + Sync + Unpin + 'static
+{
     type Discriminant: std::fmt::Display;
 
+    /// Bump whenever the payload changes shape, registering an upgrade in `upgrades` if older
+    /// payloads need reshaping to decode
+    const SCHEMA_VERSION: u32 = 1;
+
     fn discriminant(&self) -> Self::Discriminant;
+
+    /// Picks the partition of a super stream this payload is published to. Payloads sharing a
+    /// key land on the same partition, and so are consumed in the order they were sent.
+    fn routing_key(&self) -> String {
+        self.discriminant().to_string()
+    }
+
+    /// Trace context of the transaction this payload belongs to, written to the message's
+    /// application properties
+    fn trace_context(&self) -> Option<TraceContext> {
+        None
+    }
+
+    /// How payloads of older schema versions are brought up to `SCHEMA_VERSION`
+    fn upgrades() -> SchemaUpgrades {
+        SchemaUpgrades::default()
+    }
+}
+
+/// A variant sent by a newer peer that this build doesn't know about.
+///
+/// Add it to a payload enum as a last `Unknown(UnknownVariant)` variant marked
+/// `#[serde(untagged)]`, and anything that doesn't decode as one of the known variants lands in
+/// it rather than failing the whole message, so the receiver can still answer it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownVariant {
+    /// The tag as it was on the wire, e.g. `dev.thmsn.sample.call.pow`
+    pub tag: String,
+    /// The variant's fields, kept format-neutral so they survive being packed again with any
+    /// packer. `Null` for a unit variant.
+    pub content: serde_json::Value,
+}
+
+impl Serialize for UnknownVariant {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // The same shape serde gives an externally tagged variant
+        if self.content.is_null() {
+            return serializer.serialize_str(&self.tag);
+        }
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(&self.tag, &self.content)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for UnknownVariant {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UnknownVariantVisitor)
+    }
+}
+
+struct UnknownVariantVisitor;
+
+impl<'de> Visitor<'de> for UnknownVariantVisitor {
+    type Value = UnknownVariant;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a tagged enum variant")
+    }
+
+    fn visit_str<E: de::Error>(self, tag: &str) -> Result<Self::Value, E> {
+        Ok(UnknownVariant {
+            tag: tag.to_string(),
+            content: serde_json::Value::Null,
+        })
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let (tag, content) = map
+            .next_entry()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        if map.next_key::<de::IgnoredAny>()?.is_some() {
+            return Err(de::Error::invalid_length(2, &self));
+        }
+        Ok(UnknownVariant { tag, content })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::{JsonPacker, MessagePackPacker, Packer};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    enum Old {
+        #[serde(rename = "dev.thmsn.test.add")]
+        Add { lhs: u32, rhs: u32 },
+        #[serde(untagged)]
+        Unknown(UnknownVariant),
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    enum New {
+        #[serde(rename = "dev.thmsn.test.add")]
+        Add { lhs: u32, rhs: u32 },
+        #[serde(rename = "dev.thmsn.test.pow")]
+        Pow { base: u32, exponent: u32 },
+        #[serde(rename = "dev.thmsn.test.reset")]
+        Reset,
+    }
+
+    fn falls_back_to_unknown<P: Packer>() {
+        let message = P::pack(New::Add { lhs: 1, rhs: 2 }).unwrap();
+        assert!(matches!(
+            P::unpack(&message).unwrap(),
+            Old::Add { lhs: 1, rhs: 2 }
+        ));
+
+        let message = P::pack(New::Pow {
+            base: 2,
+            exponent: 8,
+        })
+        .unwrap();
+        let Old::Unknown(unknown) = P::unpack(&message).unwrap() else {
+            panic!("decoded as a known variant");
+        };
+        assert_eq!(unknown.tag, "dev.thmsn.test.pow");
+        assert_eq!(
+            unknown.content,
+            serde_json::json!({ "base": 2, "exponent": 8 })
+        );
+
+        let message = P::pack(New::Reset).unwrap();
+        let Old::Unknown(unknown) = P::unpack(&message).unwrap() else {
+            panic!("decoded as a known variant");
+        };
+        assert_eq!(unknown.tag, "dev.thmsn.test.reset");
+        assert!(unknown.content.is_null());
+
+        // Packed again, it reads as the variant it was
+        let message = P::pack(Old::Unknown(unknown)).unwrap();
+        assert!(matches!(P::unpack(&message).unwrap(), New::Reset));
+    }
+
+    #[test]
+    fn json_falls_back_to_unknown() {
+        falls_back_to_unknown::<JsonPacker>();
+    }
+
+    #[test]
+    fn message_pack_falls_back_to_unknown() {
+        falls_back_to_unknown::<MessagePackPacker>();
+    }
 }
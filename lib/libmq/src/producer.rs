@@ -0,0 +1,649 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use liberror::AnyError;
+use rabbitmq_stream_client::{
+    error::ProducerPublishError, types::Message, Dedup, Environment, NoDedup, Producer,
+};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::{
+    channel::ChannelConfiguration,
+    confirm::{self, Backlog, ConfirmError, ConfirmHandle, Tracker},
+    connection::Connection,
+    pack::{PackOptions, PackerResult},
+    transport::{producer_name, Pack, PublishError, SendOutcome, TransportProducer},
+};
+
+const PUBLISH_ATTEMPTS: u32 = 3;
+const PUBLISH_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+fn is_disconnect(error: &ProducerPublishError) -> bool {
+    matches!(
+        error,
+        ProducerPublishError::Closed | ProducerPublishError::Client(_)
+    )
+}
+
+pub(crate) struct StreamProducer {
+    connection: Arc<Connection>,
+    stream: String,
+    backlog: Backlog,
+    inner: Inner,
+}
+
+enum Inner {
+    Plain(RwLock<Producer<NoDedup>>),
+    Deduplicating(Mutex<DeduplicatingProducer>),
+}
+
+enum Confirm {
+    Wait,
+    Track(Arc<Tracker>),
+}
+
+// `Producer<Dedup>` and `Producer<NoDedup>` share no trait to publish through
+macro_rules! send_one {
+    ($producer:expr, $message:expr, $confirm:expr) => {
+        match $confirm {
+            Confirm::Wait => $producer
+                .send_with_confirm($message)
+                .await
+                .map(|status| match status.confirmed() {
+                    true => SendOutcome::Confirmed,
+                    false => SendOutcome::Rejected {
+                        code: status.status().into(),
+                    },
+                }),
+            Confirm::Track(tracker) => {
+                let tracker = tracker.clone();
+                $producer
+                    .send($message, move |status| {
+                        tracker.settle(match status {
+                            Ok(status) if status.confirmed() => SendOutcome::Confirmed,
+                            Ok(status) => SendOutcome::Rejected {
+                                code: status.status().into(),
+                            },
+                            Err(e) => SendOutcome::Failed(e.into()),
+                        });
+                        futures::future::ready(())
+                    })
+                    .await
+                    .map(|()| SendOutcome::Sent)
+            }
+        }
+    };
+}
+
+fn settled(stream: &str, outcome: SendOutcome) -> Result<(), PublishError> {
+    match outcome {
+        SendOutcome::Rejected { code } => {
+            confirm::rejected(stream, code);
+            Err(PublishError::Publish(
+                ConfirmError::Rejected { code }.into(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+enum BatchSent {
+    Published(Vec<SendOutcome>),
+    Confirming {
+        first_publishing_id: u64,
+        count: usize,
+        confirms: mpsc::Receiver<Result<(u64, bool, u16), ProducerPublishError>>,
+    },
+}
+
+// Ids are assigned here rather than by the client library, so a retried publish reuses its id
+// and the broker drops it if the first attempt made it after all
+struct DeduplicatingProducer {
+    name: String,
+    producer: Producer<Dedup>,
+    next_publishing_id: u64,
+}
+
+impl StreamProducer {
+    pub async fn build(
+        connection: &Arc<Connection>,
+        config: &ChannelConfiguration,
+        stream: &str,
+    ) -> Result<Self, AnyError> {
+        let environment = connection.environment();
+        let inner = match producer_name(config)? {
+            Some(name) => {
+                let producer = build_deduplicating(&environment, stream, name).await?;
+                let last_publishing_id = query_sequence(connection, stream, name).await?;
+                tracing::info!(
+                    stream,
+                    producer = name,
+                    last_publishing_id,
+                    "Deduplicating producer created"
+                );
+
+                Inner::Deduplicating(Mutex::new(DeduplicatingProducer {
+                    name: name.to_string(),
+                    producer,
+                    next_publishing_id: last_publishing_id + 1,
+                }))
+            }
+            None => Inner::Plain(RwLock::new(environment.producer().build(stream).await?)),
+        };
+
+        Ok(Self {
+            connection: connection.clone(),
+            stream: stream.to_string(),
+            backlog: Backlog::new(stream, &config.confirm_backlog),
+            inner,
+        })
+    }
+
+    async fn send_message(
+        &self,
+        pack: impl FnOnce(&PackOptions) -> PackerResult<Message>,
+        confirm: bool,
+    ) -> Result<(), PublishError> {
+        let confirm = match confirm {
+            true => Confirm::Wait,
+            false => Confirm::Track(self.track(None).await?),
+        };
+        self.publish(pack, confirm).await
+    }
+
+    async fn send_tracked(
+        &self,
+        pack: impl FnOnce(&PackOptions) -> PackerResult<Message>,
+    ) -> Result<ConfirmHandle, PublishError> {
+        let (handle, confirmed) = oneshot::channel();
+        let tracker = self.track(Some(handle)).await?;
+        self.publish(pack, Confirm::Track(tracker)).await?;
+        Ok(ConfirmHandle::new(confirmed))
+    }
+
+    async fn track(
+        &self,
+        handle: Option<oneshot::Sender<SendOutcome>>,
+    ) -> Result<Arc<Tracker>, PublishError> {
+        let slot = self
+            .backlog
+            .acquire()
+            .await
+            .map_err(|e| PublishError::Publish(e.into()))?;
+        Ok(Arc::new(Tracker::new(&self.stream, slot, handle)))
+    }
+
+    async fn publish(
+        &self,
+        pack: impl FnOnce(&PackOptions) -> PackerResult<Message>,
+        confirm: Confirm,
+    ) -> Result<(), PublishError> {
+        match &self.inner {
+            Inner::Plain(producer) => {
+                let message = pack(&PackOptions::default()).map_err(PublishError::Pack)?;
+                let current = producer.read().unwrap_or_else(|e| e.into_inner()).clone();
+                let published = match publish_plain(&current, message.clone(), &confirm).await {
+                    Err(e) if is_disconnect(&e) => {
+                        tracing::warn!(stream = self.stream, "Producer disconnected: {e}");
+                        let rebuilt = self
+                            .connection
+                            .recover("producer", |environment| {
+                                let stream = self.stream.clone();
+                                async move { environment.producer().build(&stream).await }
+                            })
+                            .await
+                            .map_err(PublishError::Publish)?;
+                        *producer.write().unwrap_or_else(|e| e.into_inner()) = rebuilt.clone();
+                        publish_plain(&rebuilt, message, &confirm).await
+                    }
+                    published => published,
+                };
+                settled(
+                    &self.stream,
+                    published.map_err(|e| PublishError::Publish(e.into()))?,
+                )
+            }
+            Inner::Deduplicating(producer) => {
+                // The lock only covers assigning the id and sending, waiting for the confirm
+                // happens after it's released so other sends can go ahead meanwhile
+                let (tracker, confirmed) = match confirm {
+                    Confirm::Wait => {
+                        let (handle, confirmed) = oneshot::channel();
+                        let tracker = Tracker::new(&self.stream, None, Some(handle));
+                        (Arc::new(tracker), Some(confirmed))
+                    }
+                    Confirm::Track(tracker) => (tracker, None),
+                };
+                producer
+                    .lock()
+                    .await
+                    .publish(&self.connection, &self.stream, pack, tracker)
+                    .await?;
+
+                let Some(confirmed) = confirmed else {
+                    return Ok(());
+                };
+                // The tracker has already reported what went wrong
+                match ConfirmHandle::new(confirmed).await {
+                    SendOutcome::Rejected { code } => Err(PublishError::Publish(
+                        ConfirmError::Rejected { code }.into(),
+                    )),
+                    SendOutcome::Failed(e) => Err(PublishError::Publish(e)),
+                    SendOutcome::Sent | SendOutcome::Confirmed => Ok(()),
+                }
+            }
+        }
+    }
+
+    async fn publish_batch(&self, packs: Vec<Pack<'_>>, confirm: bool) -> Vec<SendOutcome> {
+        match &self.inner {
+            Inner::Plain(producer) => {
+                let (mut outcomes, messages) = pack_batch(packs, |_| PackOptions::default());
+                let published = {
+                    let current = producer.read().unwrap_or_else(|e| e.into_inner()).clone();
+                    match publish_plain_batch(&self.stream, &current, messages.clone(), confirm)
+                        .await
+                    {
+                        Err(e) if is_disconnect(&e) => {
+                            tracing::warn!(stream = self.stream, "Producer disconnected: {e}");
+                            let rebuilt = self
+                                .connection
+                                .recover("producer", |environment| {
+                                    let stream = self.stream.clone();
+                                    async move { environment.producer().build(&stream).await }
+                                })
+                                .await;
+                            match rebuilt {
+                                Ok(rebuilt) => {
+                                    *producer.write().unwrap_or_else(|e| e.into_inner()) =
+                                        rebuilt.clone();
+                                    publish_plain_batch(&self.stream, &rebuilt, messages, confirm)
+                                        .await
+                                        .map_err(AnyError::from)
+                                }
+                                Err(e) => Err(e),
+                            }
+                        }
+                        published => published.map_err(AnyError::from),
+                    }
+                };
+                fill_batch(&mut outcomes, published)
+            }
+            Inner::Deduplicating(producer) => {
+                let (mut outcomes, sent) = producer
+                    .lock()
+                    .await
+                    .publish_batch(&self.connection, &self.stream, packs, confirm)
+                    .await;
+                let published = match sent {
+                    Ok(BatchSent::Published(published)) => Ok(published),
+                    Ok(BatchSent::Confirming {
+                        first_publishing_id,
+                        count,
+                        confirms,
+                    }) => Ok(
+                        confirmed_batch(&self.stream, first_publishing_id, count, confirms).await,
+                    ),
+                    Err(e) => Err(e),
+                };
+                fill_batch(&mut outcomes, published)
+            }
+        }
+    }
+}
+
+impl TransportProducer for StreamProducer {
+    fn send<'a>(
+        &'a self,
+        pack: Pack<'a>,
+        confirm: bool,
+    ) -> BoxFuture<'a, Result<(), PublishError>> {
+        self.send_message(pack, confirm).boxed()
+    }
+
+    fn send_tracked<'a>(
+        &'a self,
+        pack: Pack<'a>,
+    ) -> BoxFuture<'a, Result<ConfirmHandle, PublishError>> {
+        StreamProducer::send_tracked(self, pack).boxed()
+    }
+
+    fn send_batch<'a>(
+        &'a self,
+        packs: Vec<Pack<'a>>,
+        confirm: bool,
+    ) -> BoxFuture<'a, Vec<SendOutcome>> {
+        self.publish_batch(packs, confirm).boxed()
+    }
+}
+
+fn pack_batch(
+    packs: Vec<Pack<'_>>,
+    mut options: impl FnMut(usize) -> PackOptions,
+) -> (Vec<Option<SendOutcome>>, Vec<Message>) {
+    let mut outcomes = Vec::with_capacity(packs.len());
+    let mut messages = Vec::with_capacity(packs.len());
+    for pack in packs {
+        match pack(&options(messages.len())) {
+            Ok(message) => {
+                outcomes.push(None);
+                messages.push(message);
+            }
+            Err(e) => outcomes.push(Some(SendOutcome::Failed(e.into()))),
+        }
+    }
+    (outcomes, messages)
+}
+
+fn fill_batch(
+    outcomes: &mut [Option<SendOutcome>],
+    published: Result<Vec<SendOutcome>, AnyError>,
+) -> Vec<SendOutcome> {
+    let open = outcomes.iter_mut().filter(|outcome| outcome.is_none());
+    match published {
+        Ok(published) => open
+            .zip(published)
+            .for_each(|(outcome, published)| *outcome = Some(published)),
+        Err(e) => open.for_each(|outcome| *outcome = Some(SendOutcome::Failed(e.clone()))),
+    }
+    outcomes
+        .iter_mut()
+        .map(|outcome| {
+            outcome.take().unwrap_or_else(|| {
+                SendOutcome::Failed(io::Error::other("the broker didn't account for it").into())
+            })
+        })
+        .collect()
+}
+
+// Confirms may come back in any order
+fn confirmations(stream: &str, mut statuses: Vec<(u64, bool, u16)>) -> Vec<SendOutcome> {
+    statuses.sort_by_key(|(publishing_id, _, _)| *publishing_id);
+    statuses
+        .into_iter()
+        .map(|(_, confirmed, code)| confirmation(stream, confirmed, code))
+        .collect()
+}
+
+fn confirmation(stream: &str, confirmed: bool, code: u16) -> SendOutcome {
+    match confirmed {
+        true => SendOutcome::Confirmed,
+        false => {
+            confirm::rejected(stream, code);
+            SendOutcome::Rejected { code }
+        }
+    }
+}
+
+// The confirmation's type isn't exported, so this can't be a function
+macro_rules! report_rejected {
+    ($stream:expr) => {{
+        let stream = $stream.to_string();
+        move |status: Result<_, ProducerPublishError>| {
+            if let Ok(status) = status {
+                if !status.confirmed() {
+                    confirm::rejected(&stream, status.status().into());
+                }
+            }
+            futures::future::ready(())
+        }
+    }};
+}
+
+async fn confirmed_batch(
+    stream: &str,
+    first_publishing_id: u64,
+    count: usize,
+    mut confirms: mpsc::Receiver<Result<(u64, bool, u16), ProducerPublishError>>,
+) -> Vec<SendOutcome> {
+    let mut outcomes = vec![None; count];
+    let mut failed = None;
+    while let Some(status) = confirms.recv().await {
+        match status {
+            Ok((publishing_id, confirmed, code)) => {
+                let index = publishing_id.checked_sub(first_publishing_id);
+                if let Some(outcome) = index.and_then(|index| outcomes.get_mut(index as usize)) {
+                    *outcome = Some(confirmation(stream, confirmed, code));
+                }
+            }
+            Err(e) => failed = Some(AnyError::from(e)),
+        }
+    }
+    // A failed confirm doesn't say which message it was for, only those still unaccounted for
+    // are failed, the rest keep what the broker said about them
+    let failed = failed.unwrap_or_else(|| io::Error::other("no confirm arrived").into());
+    outcomes
+        .into_iter()
+        .map(|outcome| outcome.unwrap_or_else(|| SendOutcome::Failed(failed.clone())))
+        .collect()
+}
+
+async fn publish_plain_batch(
+    stream: &str,
+    producer: &Producer<NoDedup>,
+    messages: Vec<Message>,
+    confirm: bool,
+) -> Result<Vec<SendOutcome>, ProducerPublishError> {
+    if messages.is_empty() {
+        return Ok(vec![]);
+    }
+    if confirm {
+        let statuses = producer.batch_send_with_confirm(messages).await?;
+        Ok(confirmations(
+            stream,
+            statuses
+                .iter()
+                .map(|status| {
+                    (
+                        status.publishing_id(),
+                        status.confirmed(),
+                        status.status().into(),
+                    )
+                })
+                .collect(),
+        ))
+    } else {
+        let count = messages.len();
+        producer
+            .batch_send(messages, report_rejected!(stream))
+            .await?;
+        Ok(vec![SendOutcome::Sent; count])
+    }
+}
+
+async fn publish_plain(
+    producer: &Producer<NoDedup>,
+    message: Message,
+    confirm: &Confirm,
+) -> Result<SendOutcome, ProducerPublishError> {
+    send_one!(producer, message, confirm)
+}
+
+async fn query_sequence(
+    connection: &Connection,
+    stream: &str,
+    name: &str,
+) -> Result<u64, AnyError> {
+    let client = connection.client().await?;
+    let last_publishing_id = client.query_publisher_sequence(name, stream).await?;
+    if let Err(e) = client.close().await {
+        tracing::warn!(
+            stream,
+            producer = name,
+            "Failed to close sequence client: {e}"
+        );
+    }
+    Ok(last_publishing_id)
+}
+
+async fn build_deduplicating(
+    environment: &Environment,
+    stream: &str,
+    name: &str,
+) -> Result<Producer<Dedup>, AnyError> {
+    Ok(environment.producer().name(name).build(stream).await?)
+}
+
+impl DeduplicatingProducer {
+    async fn publish(
+        &mut self,
+        connection: &Connection,
+        stream: &str,
+        pack: impl FnOnce(&PackOptions) -> PackerResult<Message>,
+        tracker: Arc<Tracker>,
+    ) -> Result<(), PublishError> {
+        let confirm = Confirm::Track(tracker.clone());
+        let publishing_id = self.next_publishing_id;
+        let message = pack(&PackOptions {
+            publishing_id: Some(publishing_id),
+            ..PackOptions::default()
+        })
+        .map_err(PublishError::Pack)?;
+
+        let mut attempt = 1;
+        let outcome = loop {
+            match send_one!(self.producer, message.clone(), &confirm) {
+                Ok(outcome) => break outcome,
+                Err(e) if is_disconnect(&e) => {
+                    tracing::warn!(stream, publishing_id, "Producer disconnected: {e}");
+                    let last_publishing_id = self
+                        .recover(connection, stream)
+                        .await
+                        .map_err(PublishError::Publish)?;
+                    // Otherwise the same id goes out again on the new producer
+                    if last_publishing_id >= publishing_id {
+                        tracker.settle(SendOutcome::Confirmed);
+                        break SendOutcome::Sent;
+                    }
+                }
+                Err(e) if attempt < PUBLISH_ATTEMPTS => {
+                    tracing::warn!(publishing_id, attempt, "Retrying publish: {e}");
+                    tokio::time::sleep(PUBLISH_RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(PublishError::Publish(e.into())),
+            }
+        };
+
+        // A rejected message spent its id all the same
+        self.next_publishing_id = self.next_publishing_id.max(publishing_id + 1);
+        settled(stream, outcome)
+    }
+
+    async fn recover(&mut self, connection: &Connection, stream: &str) -> Result<u64, AnyError> {
+        let name = &self.name;
+        self.producer = connection
+            .recover("producer", |environment| async move {
+                build_deduplicating(&environment, stream, name).await
+            })
+            .await?;
+        let last_publishing_id = query_sequence(connection, stream, name).await?;
+        self.next_publishing_id = self.next_publishing_id.max(last_publishing_id + 1);
+        Ok(last_publishing_id)
+    }
+
+    async fn publish_batch(
+        &mut self,
+        connection: &Connection,
+        stream: &str,
+        packs: Vec<Pack<'_>>,
+        confirm: bool,
+    ) -> (Vec<Option<SendOutcome>>, Result<BatchSent, AnyError>) {
+        let first_publishing_id = self.next_publishing_id;
+        let (outcomes, messages) = pack_batch(packs, |index| PackOptions {
+            publishing_id: Some(first_publishing_id + index as u64),
+            ..PackOptions::default()
+        });
+        let count = messages.len() as u64;
+
+        let mut attempt = 1;
+        let sent = loop {
+            let sent = if messages.is_empty() {
+                Ok(BatchSent::Published(vec![]))
+            } else if confirm {
+                // Confirms of an attempt that failed never arrive, each attempt gets its own channel
+                let (confirmed, confirms) = mpsc::channel(messages.len());
+                self.producer
+                    .batch_send(messages.clone(), move |status| {
+                        let confirmed = confirmed.clone();
+                        async move {
+                            let status = status.map(|status| {
+                                (
+                                    status.publishing_id(),
+                                    status.confirmed(),
+                                    status.status().into(),
+                                )
+                            });
+                            let _ = confirmed.send(status).await;
+                        }
+                    })
+                    .await
+                    .map(|()| BatchSent::Confirming {
+                        first_publishing_id,
+                        count: messages.len(),
+                        confirms,
+                    })
+            } else {
+                self.producer
+                    .batch_send(messages.clone(), report_rejected!(stream))
+                    .await
+                    .map(|()| BatchSent::Published(vec![SendOutcome::Sent; messages.len()]))
+            };
+
+            match sent {
+                Ok(sent) => break Ok(sent),
+                Err(e) if is_disconnect(&e) => {
+                    tracing::warn!(stream, first_publishing_id, "Producer disconnected: {e}");
+                    // Resent as is, the broker drops whichever ids it already has
+                    if let Err(e) = self.recover(connection, stream).await {
+                        break Err(e);
+                    }
+                }
+                Err(e) if attempt < PUBLISH_ATTEMPTS => {
+                    tracing::warn!(first_publishing_id, attempt, "Retrying batch publish: {e}");
+                    tokio::time::sleep(PUBLISH_RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => break Err(e.into()),
+            }
+        };
+
+        // Ids are spent whether or not the broker took them, reusing one would get a later
+        // message dropped as a duplicate
+        self.next_publishing_id = self.next_publishing_id.max(first_publishing_id + count);
+        (outcomes, sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_failed_confirm_fails_only_its_message() {
+        let (confirmed, confirms) = mpsc::channel(3);
+        confirmed.send(Ok((12, true, 1))).await.unwrap();
+        confirmed
+            .send(Err(ProducerPublishError::Confirmation {
+                stream: "calls".into(),
+            }))
+            .await
+            .unwrap();
+        confirmed.send(Ok((10, true, 1))).await.unwrap();
+        drop(confirmed);
+
+        let outcomes = confirmed_batch("calls", 10, 3, confirms).await;
+        assert!(matches!(
+            outcomes.as_slice(),
+            [
+                SendOutcome::Confirmed,
+                SendOutcome::Failed(_),
+                SendOutcome::Confirmed
+            ]
+        ));
+    }
+}
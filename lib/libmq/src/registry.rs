@@ -0,0 +1,236 @@
+use std::{borrow::Cow, marker::PhantomData};
+
+use rabbitmq_stream_client::types::{Message, SimpleValue};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::pack::{
+    content_type, Encoded, PackOptions, Packer, PackerError, PackerResult, PackerValidateError,
+};
+
+/// A tuple of packers, looked up by content type
+pub trait PackerSet: std::fmt::Debug + Send + Sync + Unpin + 'static {
+    /// Content type of the first packer, used when none is picked
+    const DEFAULT_CONTENT_TYPE: &'static str;
+
+    fn content_types() -> Vec<&'static str>;
+    fn supports(content_type: &str) -> bool;
+
+    fn pack_with<Payload: Serialize>(
+        content_type: &str,
+        payload: Payload,
+        options: &PackOptions,
+    ) -> PackerResult<Message>;
+    fn validate(content_type: &str, message: &Message) -> PackerResult<()>;
+    fn body<'a>(content_type: &str, message: &'a Message) -> PackerResult<Cow<'a, [u8]>>;
+    fn de_message<Payload: DeserializeOwned>(
+        content_type: &str,
+        message: &Message,
+        bytes: &[u8],
+    ) -> PackerResult<Payload>;
+}
+
+macro_rules! packer_set {
+    ($first:ident $(, $rest:ident)*) => {
+        impl<$first: Packer $(, $rest: Packer)*> PackerSet for ($first, $($rest,)*) {
+            const DEFAULT_CONTENT_TYPE: &'static str = $first::CONTENT_TYPE;
+
+            fn content_types() -> Vec<&'static str> {
+                vec![$first::CONTENT_TYPE $(, $rest::CONTENT_TYPE)*]
+            }
+
+            fn supports(content_type: &str) -> bool {
+                $first::supports(content_type) $(|| $rest::supports(content_type))*
+            }
+
+            fn pack_with<Payload: Serialize>(
+                content_type: &str,
+                payload: Payload,
+                options: &PackOptions,
+            ) -> PackerResult<Message> {
+                if $first::supports(content_type) {
+                    return $first::pack_with(payload, options);
+                }
+                $(if $rest::supports(content_type) {
+                    return $rest::pack_with(payload, options);
+                })*
+                Err(PackerError::UnsupportedContentType(content_type.to_string()))
+            }
+
+            fn validate(content_type: &str, message: &Message) -> PackerResult<()> {
+                if $first::supports(content_type) {
+                    return $first::validate(message);
+                }
+                $(if $rest::supports(content_type) {
+                    return $rest::validate(message);
+                })*
+                Err(unregistered::<Self>(content_type))
+            }
+
+            fn body<'a>(content_type: &str, message: &'a Message) -> PackerResult<Cow<'a, [u8]>> {
+                if $first::supports(content_type) {
+                    return $first::body(message);
+                }
+                $(if $rest::supports(content_type) {
+                    return $rest::body(message);
+                })*
+                Err(unregistered::<Self>(content_type))
+            }
+
+            fn de_message<Payload: DeserializeOwned>(
+                content_type: &str,
+                message: &Message,
+                bytes: &[u8],
+            ) -> PackerResult<Payload> {
+                if $first::supports(content_type) {
+                    return $first::de_message(message, bytes);
+                }
+                $(if $rest::supports(content_type) {
+                    return $rest::de_message(message, bytes);
+                })*
+                Err(unregistered::<Self>(content_type))
+            }
+        }
+    };
+}
+
+packer_set!(A);
+packer_set!(A, B);
+packer_set!(A, B, C);
+packer_set!(A, B, C, D);
+packer_set!(A, B, C, D, E);
+packer_set!(A, B, C, D, E, F);
+
+fn unregistered<S: PackerSet>(content_type: &str) -> PackerError {
+    PackerValidateError::IncorrectPropertyValue {
+        property_name: "content_type".into(),
+        expected_value: S::content_types().join(", "),
+        actual_value: content_type.to_string(),
+    }
+    .into()
+}
+
+/// Packs with whichever of `S` the channel's `content_type` picks, the first by default, and
+/// unpacks anything packed by any of them. Lets producers move to a new encoding while
+/// consumers still read the old one, without a flag day.
+///
+/// Wrappers such as `Compressed` go inside the set, around the packers they apply to.
+#[derive(Debug)]
+pub struct Registry<S: PackerSet>(PhantomData<S>);
+
+impl<S: PackerSet> Packer for Registry<S> {
+    const CONTENT_TYPE: &'static str = S::DEFAULT_CONTENT_TYPE;
+
+    fn ser<Payload: Serialize>(_payload: &Payload) -> PackerResult<Vec<u8>> {
+        // Only reached through `Packer::encode` chains, which a registry doesn't sit in
+        Err(PackerError::UnsupportedContentType(
+            Self::CONTENT_TYPE.to_string(),
+        ))
+    }
+
+    fn de<Payload: DeserializeOwned>(_bytes: &[u8]) -> PackerResult<Payload> {
+        Err(PackerError::UnsupportedContentType(
+            Self::CONTENT_TYPE.to_string(),
+        ))
+    }
+
+    fn supports(content_type: &str) -> bool {
+        S::supports(content_type)
+    }
+
+    fn de_message<Payload: DeserializeOwned>(
+        message: &Message,
+        bytes: &[u8],
+    ) -> PackerResult<Payload> {
+        S::de_message(content_type(message)?, message, bytes)
+    }
+
+    fn encode(
+        _bytes: Vec<u8>,
+        _properties: &[(&'static str, SimpleValue)],
+    ) -> PackerResult<Encoded> {
+        Err(PackerError::UnsupportedContentType(
+            Self::CONTENT_TYPE.to_string(),
+        ))
+    }
+
+    fn body(message: &Message) -> PackerResult<Cow<'_, [u8]>> {
+        S::body(content_type(message)?, message)
+    }
+
+    fn pack_with<Payload: Serialize>(
+        payload: Payload,
+        options: &PackOptions,
+    ) -> PackerResult<Message> {
+        let content_type = options
+            .content_type
+            .as_deref()
+            .unwrap_or(S::DEFAULT_CONTENT_TYPE);
+        S::pack_with(content_type, payload, options)
+    }
+
+    fn validate(message: &Message) -> PackerResult<()> {
+        S::validate(content_type(message)?, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::{JsonPacker, MessagePackPacker};
+
+    type Set = Registry<(MessagePackPacker, JsonPacker)>;
+
+    #[test]
+    fn unpacks_every_registered_content_type() {
+        let payload = vec!["one".to_string(), "two".to_string()];
+        for message in [
+            MessagePackPacker::pack(&payload).unwrap(),
+            JsonPacker::pack(&payload).unwrap(),
+        ] {
+            assert_eq!(Set::unpack::<Vec<String>>(&message).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn packs_with_the_picked_content_type() {
+        let packed = |picked: Option<&str>| {
+            let options = PackOptions {
+                content_type: picked.map(str::to_string),
+                ..PackOptions::default()
+            };
+            let message = Set::pack_with("payload", &options).unwrap();
+            content_type(&message).unwrap().to_string()
+        };
+        assert_eq!(packed(None), MessagePackPacker::CONTENT_TYPE);
+        assert_eq!(
+            packed(Some(JsonPacker::CONTENT_TYPE)),
+            JsonPacker::CONTENT_TYPE
+        );
+        assert!(matches!(
+            Set::pack_with(
+                "payload",
+                &PackOptions {
+                    content_type: Some("text/plain".into()),
+                    ..PackOptions::default()
+                }
+            ),
+            Err(PackerError::UnsupportedContentType(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unregistered_content_types() {
+        let message = Message::builder()
+            .body(b"payload".to_vec())
+            .properties()
+            .content_type("text/plain")
+            .message_builder()
+            .build();
+        assert!(matches!(
+            Set::unpack::<String>(&message),
+            Err(PackerError::Validation(
+                PackerValidateError::IncorrectPropertyValue { .. }
+            ))
+        ));
+    }
+}
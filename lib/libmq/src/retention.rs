@@ -0,0 +1,64 @@
+use std::{fmt::Display, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// How much of a stream the broker keeps before discarding its oldest segments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retention {
+    pub max_length_bytes: Option<u64>,
+    /// Size of the segment files the stream is stored in, and so the granularity of discarding
+    pub max_segment_size_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_length_bytes: Some(1_000_000_000),
+            max_segment_size_bytes: None,
+            max_age: None,
+        }
+    }
+}
+impl Display for Retention {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let unset = || "unset".to_string();
+        write!(
+            f,
+            "max length {}, max segment size {}, max age {}",
+            self.max_length_bytes
+                .map_or_else(unset, |bytes| format!("{bytes}B")),
+            self.max_segment_size_bytes
+                .map_or_else(unset, |bytes| format!("{bytes}B")),
+            self.max_age
+                .map_or_else(unset, |age| format!("{}s", age.as_secs())),
+        )
+    }
+}
+
+/// What to do about streams of the channel that don't exist yet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CreationPolicy {
+    /// Create missing streams, and check existing ones were created with the same retention
+    #[default]
+    CreateIfMissing,
+    /// Fail when a stream is missing, and never create one. RabbitMQ only reports mismatched
+    /// retention on create, so there existing streams go unchecked.
+    RequireExisting,
+    /// Leave the streams alone entirely, they are managed elsewhere
+    Never,
+}
+
+#[derive(Debug, Clone, Error, Serialize, Deserialize, valuable::Valuable)]
+#[serde(tag = "$type", content = "reason")]
+pub enum StreamSetupError {
+    #[error("Stream \"{stream}\" does not exist")]
+    #[serde(rename = "dev.thmsn.mq.stream.missing")]
+    Missing { stream: String },
+    #[error("Stream \"{stream}\" exists with different arguments than requested ({requested})")]
+    #[serde(rename = "dev.thmsn.mq.stream.arguments_mismatch")]
+    ArgumentsMismatch { stream: String, requested: String },
+    #[error("Failed to create stream \"{stream}\": {reason}")]
+    #[serde(rename = "dev.thmsn.mq.stream.create")]
+    Create { stream: String, reason: String },
+}
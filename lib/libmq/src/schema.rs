@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+use rabbitmq_stream_client::types::{Message, SimpleValue};
+use serde_json::Value;
+
+use crate::pack::{PackerError, PackerResult, PackerValidateError};
+
+/// Version of the `ManagerMessage` layout itself, bumped if `meta` or the payload wrapping ever
+/// change shape
+pub const ENVELOPE_VERSION: u32 = 1;
+
+pub(crate) const ENVELOPE_VERSION_PROPERTY: &str = "x-envelope-version";
+pub(crate) const SCHEMA_VERSION_PROPERTY: &str = "x-schema-version";
+
+/// Messages from before versions were sent are all version 1
+const UNVERSIONED: u32 = 1;
+
+/// Turns a payload of one schema version into the next, working on its format-neutral form
+pub type Upgrade = fn(Value) -> Result<Value, String>;
+
+/// How to bring payloads of older schema versions up to the current one, so a replayed stream
+/// still decodes after the payload type has moved on
+#[derive(Debug, Clone, Default)]
+pub struct SchemaUpgrades {
+    steps: BTreeMap<u32, Upgrade>,
+}
+impl SchemaUpgrades {
+    /// Register the step from version `from` to `from + 1`. Versions without a step are taken
+    /// to be compatible with the next one as they are.
+    pub fn with(mut self, from: u32, upgrade: Upgrade) -> Self {
+        self.steps.insert(from, upgrade);
+        self
+    }
+
+    pub fn upgrade(&self, mut value: Value, from: u32, to: u32) -> PackerResult<Value> {
+        for (version, upgrade) in self.steps.range(from..to) {
+            value = upgrade(value).map_err(|reason| PackerError::Upgrade {
+                from: *version,
+                to: version + 1,
+                reason,
+            })?;
+        }
+        Ok(value)
+    }
+}
+
+/// A version stamped on the message's application properties
+pub(crate) fn version(message: &Message, property_name: &str) -> PackerResult<u32> {
+    let Some(value) = message
+        .application_properties()
+        .and_then(|properties| properties.get(property_name))
+    else {
+        return Ok(UNVERSIONED);
+    };
+    match value {
+        SimpleValue::Uint(version) => Ok(*version),
+        value => Err(PackerValidateError::IncorrectPropertyValue {
+            property_name: property_name.to_string(),
+            expected_value: "uint".to_string(),
+            actual_value: format!("{value:?}"),
+        }
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rename(value: Value) -> Result<Value, String> {
+        let name = value.get("name").ok_or("no name")?.clone();
+        Ok(json!({ "full_name": name }))
+    }
+
+    fn tag(value: Value) -> Result<Value, String> {
+        let mut value = value;
+        value["tagged"] = json!(true);
+        Ok(value)
+    }
+
+    #[test]
+    fn runs_the_steps_in_between() {
+        let upgrades = SchemaUpgrades::default().with(1, rename).with(3, tag);
+        let value = json!({ "name": "ada" });
+        assert_eq!(
+            upgrades.upgrade(value.clone(), 1, 4).unwrap(),
+            json!({ "full_name": "ada", "tagged": true })
+        );
+        assert_eq!(
+            upgrades.upgrade(value.clone(), 1, 2).unwrap(),
+            json!({ "full_name": "ada" })
+        );
+        assert_eq!(upgrades.upgrade(value.clone(), 4, 4).unwrap(), value);
+    }
+
+    #[test]
+    fn reports_the_failing_step() {
+        let upgrades = SchemaUpgrades::default().with(2, rename);
+        assert!(matches!(
+            upgrades.upgrade(json!({}), 1, 3),
+            Err(PackerError::Upgrade { from: 2, to: 3, .. })
+        ));
+    }
+}
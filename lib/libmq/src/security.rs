@@ -0,0 +1,70 @@
+use std::fmt::Debug;
+
+use rabbitmq_stream_client::TlsConfiguration;
+
+/// A value kept out of `Debug` output, so it doesn't end up in logs and spans
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "***")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret,
+}
+impl Credentials {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: Secret::new(password),
+        }
+    }
+}
+
+/// A PEM certificate and its PKCS#8 private key, presented to the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    pub certificate_path: String,
+    pub key_path: String,
+}
+
+/// The broker's certificate is checked against the host we connect to, the stream client has no
+/// way to check it against another name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    /// PEM bundle of the authorities the broker's certificate is checked against
+    pub ca_certificate_path: Option<String>,
+    pub client_certificate: Option<ClientCertificate>,
+    /// Skip checking the broker's certificate altogether, only ever for local testing
+    pub accept_invalid_certificates: bool,
+}
+impl From<&TlsOptions> for TlsConfiguration {
+    fn from(value: &TlsOptions) -> Self {
+        let mut tls = TlsConfiguration::builder()
+            .enable(true)
+            .trust_certificates(value.accept_invalid_certificates);
+        if let Some(ca_certificate_path) = &value.ca_certificate_path {
+            tls = tls.add_root_certificates(ca_certificate_path.clone());
+        }
+        if let Some(client_certificate) = &value.client_certificate {
+            tls = tls.add_client_certificates_keys(
+                client_certificate.certificate_path.clone(),
+                client_certificate.key_path.clone(),
+            );
+        }
+        tls.build()
+    }
+}
@@ -1,23 +1,42 @@
-use std::{marker::PhantomData, time::Duration};
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
 
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 use liberror::AnyError;
 use serde::{Deserialize, Serialize};
 
-use rabbitmq_stream_client::{
-    error::StreamCreateError,
-    types::{ByteCapacity, ResponseCode},
-    Consumer, Environment, NoDedup, Producer,
-};
 use strum::Display;
 use thiserror::Error;
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
+};
 
 use crate::{
+    batch::{next_batch, BatchConfiguration},
     channel::ChannelConfiguration,
+    chunk::{self, ChunkConfiguration, ChunkError, Reassembler},
+    confirm::ConfirmHandle,
+    connection::ConnectionStatus,
+    dead_letter::DeadLetterProducer,
+    delivery::Delivery,
     message::{ManagerMessage, ManagerMessagePayload},
     meta::ManagerMeta,
-    pack::{Packer, PackerError},
+    metrics,
+    offset::{tick, OffsetCommand},
+    pack::{PackOptions, Packer, PackerError, PackerResult},
+    partition::{PartitionEvent, PartitionedConsumer},
     payload::MessageQueuePayload,
+    retention::StreamSetupError,
+    transport::{
+        Pack, PublishError, RabbitMqTransport, SendOutcome, Transport, TransportDelivery,
+        TransportProducer,
+    },
 };
 
 #[derive(
@@ -49,17 +68,55 @@ pub enum MessageQueueServerError {
     #[error("Failed to process message: {0}")]
     #[serde(rename = "dev.thmsn.mq.server.packer")]
     Packer(#[from] PackerError),
+    #[error("Failed to reassemble message: {0}")]
+    #[serde(rename = "dev.thmsn.mq.server.chunk")]
+    Chunk(#[from] ChunkError),
+    #[error("Failed to set up stream: {0}")]
+    #[serde(rename = "dev.thmsn.mq.server.stream")]
+    Stream(#[from] StreamSetupError),
+    #[error("The consumer has closed")]
+    #[serde(rename = "dev.thmsn.mq.server.closed")]
+    Closed,
+    #[error("Failed to store consumer offset: {0}")]
+    #[serde(rename = "dev.thmsn.mq.server.store_offset")]
+    StoreOffset(AnyError),
 }
 pub type MessageQueueServerResult<T> = Result<T, MessageQueueServerError>;
 
+impl From<PublishError> for MessageQueueServerError {
+    fn from(value: PublishError) -> Self {
+        match value {
+            PublishError::Pack(e) => Self::Packer(e),
+            PublishError::Publish(e) => Self::Send(e),
+        }
+    }
+}
+
+const DELIVERY_BUFFER_SIZE: usize = 1024;
+
+enum Received<TCall> {
+    Call(Delivery<TCall>),
+    // Dead-lettered, but still counts as processed
+    Skipped { partition: usize, offset: u64 },
+}
+
 pub struct MessageQueueServer<
     TCall: MessageQueuePayload,
     TResponse: MessageQueuePayload,
     TPacker: Packer,
 > {
     service_name: String,
-    producer: Producer<NoDedup>,
-    consumer: Consumer,
+    transport: Arc<dyn Transport>,
+    producer: Box<dyn TransportProducer>,
+    deliveries: mpsc::Receiver<MessageQueueServerResult<Received<TCall>>>,
+    deferred: Option<MessageQueueServerError>,
+    commands: mpsc::UnboundedSender<OffsetCommand>,
+    reader: JoinHandle<()>,
+    // Per partition, the highest offset handed out and how many calls that covers
+    unacknowledged: HashMap<usize, (u64, u64)>,
+    batch: BatchConfiguration,
+    content_type: Option<String>,
+    chunking: ChunkConfiguration,
     _phantom_call: PhantomData<TCall>,
     _phantom_response: PhantomData<TResponse>,
     _phantom_packer: PhantomData<TPacker>,
@@ -73,118 +130,542 @@ impl<TCall: MessageQueuePayload, TResponse: MessageQueuePayload, TPacker: Packer
         service_name: String,
         mq: &ChannelConfiguration,
     ) -> MessageQueueServerResult<Self> {
-        let environment = Environment::builder()
-            .host(&mq.host)
-            .port(mq.port)
-            .build()
+        let transport = RabbitMqTransport::connect(mq)
             .await
             .map_err(|e| MessageQueueServerError::CreateEnvironment(e.to_string()))?;
 
-        // Ensure the stream exists
-        let created = environment
-            .stream_creator()
-            .max_length(ByteCapacity::GB(1))
-            .create(&mq.stream_name)
-            .await;
-
-        match created {
-            Err(StreamCreateError::Create { status, .. }) => {
-                // Stream exists, ignore
-                match status {
-                    ResponseCode::StreamAlreadyExists => {}
-                    // general create error
-                    _ => {
-                        return Err(MessageQueueServerError::CreateEnvironment(format!(
-                            "{:?}",
-                            status
-                        )))
-                    }
-                }
-            }
-            // No data
-            Ok(()) => {}
-            // general error
-            _ => {
-                return Err(MessageQueueServerError::CreateEnvironment(format!(
-                    "{:?}",
-                    created
-                )))
+        Self::with_transport(service_name, mq, transport).await
+    }
+
+    /// Run over `transport` instead of connecting to RabbitMQ
+    #[tracing::instrument(name = "mq.server.with_transport", skip(transport))]
+    pub async fn with_transport(
+        service_name: String,
+        mq: &ChannelConfiguration,
+        transport: impl Transport,
+    ) -> MessageQueueServerResult<Self> {
+        if let Some(content_type) = &mq.content_type {
+            if !TPacker::supports(content_type) {
+                return Err(PackerError::UnsupportedContentType(content_type.clone()).into());
             }
         }
+        let transport: Arc<dyn Transport> = Arc::new(transport);
 
-        let producer = environment
-            .producer()
-            .build(&mq.stream_name)
-            .await
-            .map_err(|e| MessageQueueServerError::CreateProducer(e.into()))?;
+        // Ensure both halves of the channel exist
+        let reply_stream = mq.reply_stream_name();
+        let call_stream = mq.call_stream_name();
+        transport.ensure_stream(mq, &reply_stream, None).await?;
+        transport
+            .ensure_stream(mq, &call_stream, mq.call_partitions)
+            .await?;
 
-        let consumer = environment
-            .consumer()
-            .build(&mq.stream_name)
+        let producer = transport
+            .producer(mq, &reply_stream)
             .await
-            .map_err(|e| MessageQueueServerError::CreateConsumer(e.into()))?;
+            .map_err(MessageQueueServerError::CreateProducer)?;
+
+        let dead_letters = match &mq.dead_letter_stream {
+            Some(stream) => {
+                transport.ensure_stream(mq, stream, None).await?;
+                let producer = transport
+                    .producer(mq, stream)
+                    .await
+                    .map_err(MessageQueueServerError::CreateProducer)?;
+                Some(DeadLetterProducer::new(stream.clone(), producer))
+            }
+            None => None,
+        };
+
+        let super_stream = mq.call_partitions.map(|_| call_stream.clone());
+        let consumer =
+            PartitionedConsumer::build(&transport, mq, mq.call_stream_partitions(), super_stream)
+                .await
+                .map_err(MessageQueueServerError::CreateConsumer)?;
+
+        let (deliveries_tx, deliveries) = mpsc::channel(DELIVERY_BUFFER_SIZE);
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(Self::read_calls(
+            consumer,
+            Reassembler::new(&mq.chunking),
+            dead_letters,
+            deliveries_tx,
+            commands_rx,
+        ));
 
         Ok(Self {
             service_name,
+            transport,
             producer,
-            consumer,
+            deliveries,
+            deferred: None,
+            commands,
+            reader,
+            unacknowledged: HashMap::new(),
+            batch: mq.batch.clone(),
+            content_type: mq.content_type.clone(),
+            chunking: mq.chunking.clone(),
             _phantom_call: Default::default(),
             _phantom_response: Default::default(),
             _phantom_packer: Default::default(),
         })
     }
 
+    pub fn status(&self) -> ConnectionStatus {
+        self.transport.status()
+    }
+
+    pub fn status_changes(&self) -> watch::Receiver<ConnectionStatus> {
+        self.transport.status_changes()
+    }
+
+    fn packing(&self, message: ManagerMessage<TCall, TResponse>) -> Pack<'static> {
+        let content_type = self.content_type.clone();
+        Box::new(move |options| {
+            message.pack::<TPacker>(&PackOptions {
+                content_type,
+                ..options.clone()
+            })
+        })
+    }
+
+    async fn publish(
+        &self,
+        message: ManagerMessage<TCall, TResponse>,
+        confirm: bool,
+    ) -> Result<(), PublishError> {
+        chunk::send(
+            self.producer.as_ref(),
+            self.packing(message),
+            confirm,
+            &self.chunking,
+        )
+        .await
+    }
+
     fn pack_response(&self, response: TResponse) -> ManagerMessage<TCall, TResponse> {
         ManagerMessage::new_response(ManagerMeta::new(&self.service_name), response)
     }
 
+    fn pack_reply(
+        &self,
+        call: &ManagerMeta,
+        response: TResponse,
+    ) -> ManagerMessage<TCall, TResponse> {
+        ManagerMessage::new_response(call.clone().reply(&self.service_name), response)
+    }
+
     #[tracing::instrument(name = "mq.server.send", skip(self))]
     pub async fn send(&self, response: TResponse) -> MessageQueueServerResult<()> {
-        let message = TPacker::pack(self.pack_response(response))?;
-        self.producer
-            // .send_with_confirm(message)
-            .send(message, |_| async {})
-            .await
-            .map_err(|e| MessageQueueServerError::Send(e.into()))?;
+        let message = self.pack_response(response);
+        self.publish(message, false).await?;
         Ok(())
     }
     #[tracing::instrument(name = "mq.server.send_with_confirm", skip(self))]
     pub async fn send_with_confirm(&self, response: TResponse) -> MessageQueueServerResult<()> {
-        let message = TPacker::pack(self.pack_response(response))?;
-        self.producer
-            .send_with_confirm(message)
-            .await
-            .map_err(|e| MessageQueueServerError::Send(e.into()))?;
+        let message = self.pack_response(response);
+        self.publish(message, true).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "mq.server.send_tracked", skip(self))]
+    pub async fn send_tracked(
+        &self,
+        response: TResponse,
+    ) -> MessageQueueServerResult<ConfirmHandle> {
+        let message = self.pack_response(response);
+        let pack = self.packing(message);
+        Ok(chunk::send_tracked(self.producer.as_ref(), pack, &self.chunking).await?)
+    }
+
+    /// One outcome per response, in order
+    #[tracing::instrument(name = "mq.server.send_batch", skip_all, fields(count = responses.len()))]
+    pub async fn send_batch(&self, responses: Vec<TResponse>) -> Vec<SendOutcome> {
+        self.publish_batch(responses, false).await
+    }
+
+    #[tracing::instrument(name = "mq.server.send_batch_with_confirm", skip_all, fields(count = responses.len()))]
+    pub async fn send_batch_with_confirm(&self, responses: Vec<TResponse>) -> Vec<SendOutcome> {
+        self.publish_batch(responses, true).await
+    }
+
+    async fn publish_batch(&self, responses: Vec<TResponse>, confirm: bool) -> Vec<SendOutcome> {
+        let packs = responses
+            .into_iter()
+            .map(|response| self.packing(self.pack_response(response)))
+            .collect();
+        let outcomes =
+            chunk::send_batch(self.producer.as_ref(), packs, confirm, &self.chunking).await;
+
+        let failed = outcomes
+            .iter()
+            .filter(|outcome| !outcome.is_success())
+            .count();
+        if failed > 0 {
+            tracing::warn!(failed, "Some responses of the batch didn't make it");
+        }
+        outcomes
+    }
+
+    /// Answer the call `call` describes, resolving the client's pending `call`
+    #[tracing::instrument(name = "mq.server.reply", skip(self, call), fields(parent_id = %call.request_id))]
+    pub async fn reply(
+        &self,
+        call: &ManagerMeta,
+        response: TResponse,
+    ) -> MessageQueueServerResult<()> {
+        let message = self.pack_reply(call, response);
+        self.publish(message, false).await?;
+        Ok(())
+    }
+    #[tracing::instrument(name = "mq.server.reply_with_confirm", skip(self, call), fields(parent_id = %call.request_id))]
+    pub async fn reply_with_confirm(
+        &self,
+        call: &ManagerMeta,
+        response: TResponse,
+    ) -> MessageQueueServerResult<()> {
+        let message = self.pack_reply(call, response);
+        self.publish(message, true).await?;
         Ok(())
     }
 
-    pub async fn recv(&mut self) -> MessageQueueServerResult<Vec<TCall>> {
-        let mut messages = vec![];
+    pub async fn recv(&mut self) -> MessageQueueServerResult<Vec<Delivery<TCall>>> {
+        let batch = self.batch.clone();
+        self.recv_batch(&batch).await
+    }
+
+    /// Calls handed out by the previous batch count as processed from here on
+    pub async fn recv_batch(
+        &mut self,
+        batch: &BatchConfiguration,
+    ) -> MessageQueueServerResult<Vec<Delivery<TCall>>> {
+        self.acknowledge();
+
+        let (deliveries, unacknowledged) = (&mut self.deliveries, &mut self.unacknowledged);
+        let mut calls =
+            futures::stream::poll_fn(|cx| Self::poll_call(deliveries, unacknowledged, cx));
+        next_batch(&mut calls, batch, &mut self.deferred)
+            .await
+            .unwrap_or(Err(MessageQueueServerError::Closed))
+    }
+
+    /// `recv_batch` as a stream, ending when the consumer closes
+    pub fn batched(
+        &mut self,
+        batch: BatchConfiguration,
+    ) -> impl Stream<Item = MessageQueueServerResult<Vec<Delivery<TCall>>>> + '_ {
+        futures::stream::unfold(self, move |server| {
+            let batch = batch.clone();
+            async move {
+                match server.recv_batch(&batch).await {
+                    Err(MessageQueueServerError::Closed) => None,
+                    calls => Some((calls, server)),
+                }
+            }
+        })
+    }
+
+    /// Store the offset of every call handed out so far, whatever the commit policy. Dropping
+    /// the server doesn't, so commit first to resume where it left off.
+    #[tracing::instrument(name = "mq.server.commit", skip(self))]
+    pub async fn commit(&mut self) -> MessageQueueServerResult<()> {
+        self.acknowledge();
+
+        let (done, stored) = oneshot::channel();
+        self.commands
+            .send(OffsetCommand::Commit(done))
+            .map_err(|_| MessageQueueServerError::Closed)?;
+        stored
+            .await
+            .map_err(|_| MessageQueueServerError::Closed)?
+            .map_err(MessageQueueServerError::StoreOffset)
+    }
+
+    fn poll_call(
+        deliveries: &mut mpsc::Receiver<MessageQueueServerResult<Received<TCall>>>,
+        unacknowledged: &mut HashMap<usize, (u64, u64)>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<MessageQueueServerResult<Delivery<TCall>>>> {
+        let mut hand_out = |partition: usize, offset: u64| {
+            let (highest, count) = unacknowledged.entry(partition).or_insert((offset, 0));
+            *highest = (*highest).max(offset);
+            *count += 1;
+        };
         loop {
-            let result =
-                tokio::time::timeout(Duration::from_micros(10), self.consumer.next()).await;
-            let delivery = match result {
-                Ok(Some(delivery)) => {
-                    delivery.map_err(|e| MessageQueueServerError::Receive(e.into()))?
+            match deliveries.poll_recv(cx) {
+                Poll::Ready(Some(Ok(Received::Skipped { partition, offset }))) => {
+                    hand_out(partition, offset);
+                }
+                Poll::Ready(Some(Ok(Received::Call(call)))) => {
+                    hand_out(call.partition, call.offset);
+                    return Poll::Ready(Some(Ok(call)));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        for (partition, (offset, count)) in self.unacknowledged.drain() {
+            // A closed reader has nothing left to commit to
+            let _ = self.commands.send(OffsetCommand::Processed {
+                partition,
+                offset,
+                count,
+            });
+        }
+    }
+
+    async fn read_calls(
+        mut consumer: PartitionedConsumer,
+        mut chunks: Reassembler,
+        dead_letters: Option<DeadLetterProducer>,
+        deliveries: mpsc::Sender<MessageQueueServerResult<Received<TCall>>>,
+        mut commands: mpsc::UnboundedReceiver<OffsetCommand>,
+    ) {
+        let mut interval = consumer.interval();
+        'read: loop {
+            tokio::select! {
+                delivery = consumer.next() => {
+                    let call = match delivery {
+                        Some((partition, PartitionEvent::Delivery(Ok(delivery)))) => {
+                            let delivery = match chunks.accept(delivery) {
+                                Ok(Some(delivery)) => delivery,
+                                Ok(None) => continue,
+                                Err(e) => {
+                                    if deliveries.send(Err(e.into())).await.is_err() {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            };
+                            match Self::decode(&delivery) {
+                                Ok(Some(call)) if call.meta.is_expired() => {
+                                    Self::drop_expired(consumer.stream(partition), &call);
+                                    Ok(Received::Skipped { partition, offset: delivery.offset })
+                                }
+                                Ok(Some(call)) => Ok(Received::Call(call.with_partition(partition))),
+                                Ok(None) => continue,
+                                Err(e) => match &dead_letters {
+                                    Some(dead_letters) => {
+                                        let stream = consumer.stream(partition);
+                                        match dead_letters.send(stream, &delivery, &e).await {
+                                            Ok(()) => Ok(Received::Skipped { partition, offset: delivery.offset }),
+                                            Err(dead_letter_error) => {
+                                                tracing::error!(stream, offset = delivery.offset, "Failed to dead-letter message: {dead_letter_error}");
+                                                Err(e.into())
+                                            }
+                                        }
+                                    }
+                                    None => Err(e.into()),
+                                },
+                            }
+                        }
+                        Some((_, PartitionEvent::Delivery(Err(e)))) => {
+                            Err(MessageQueueServerError::Receive(e))
+                        }
+                        Some((partition, PartitionEvent::Closed)) => {
+                            match consumer.recover(partition).await {
+                                Ok(()) => continue,
+                                Err(e) => {
+                                    let _ = deliveries.send(Err(MessageQueueServerError::Receive(e))).await;
+                                    break;
+                                }
+                            }
+                        }
+                        None => break,
+                    };
+                    if deliveries.send(call).await.is_err() {
+                        break;
+                    }
                 }
-                Ok(None) => break,
-                Err(_elapsed) => break,
-            };
-
-            let message = delivery.message();
-            let payload: ManagerMessage<TCall, TResponse> = TPacker::unpack(message)?;
-            match payload.payload {
-                ManagerMessagePayload::Call(manager_call) => {
-                    let disc = manager_call.discriminant();
-                    tracing::trace!("recv call {}: {}", delivery.offset(), disc);
-                    messages.push(manager_call);
+                command = commands.recv() => match command {
+                    Some(OffsetCommand::Processed { partition, offset, count }) => {
+                        if let Err(e) = consumer.processed(partition, offset, count).await {
+                            tracing::warn!("Failed to store consumer offset: {e}");
+                        }
+                    }
+                    Some(OffsetCommand::Commit(done)) => {
+                        let _ = done.send(consumer.store().await);
+                    }
+                    None => break,
+                },
+                _ = tick(&mut interval) => {
+                    if let Err(e) = consumer.store().await {
+                        tracing::warn!("Failed to store consumer offset: {e}");
+                    }
                 }
-                ManagerMessagePayload::Response(manager_response) => {
-                    let disc = manager_response.discriminant();
-                    tracing::trace!("ignore recv'd response {}: {}", delivery.offset(), disc);
+                _ = chunks.next_expiry() => {
+                    for e in chunks.expire() {
+                        tracing::warn!("{e}");
+                        if deliveries.send(Err(e.into())).await.is_err() {
+                            break 'read;
+                        }
+                    }
                 }
             }
         }
-        Ok(messages)
+
+        if let Err(e) = consumer.store().await {
+            tracing::warn!("Failed to store consumer offset: {e}");
+        }
+        tracing::warn!("Call consumer closed");
+    }
+
+    fn drop_expired(stream: &str, call: &Delivery<TCall>) {
+        tracing::warn!(
+            stream,
+            offset = call.offset,
+            request_id = %call.meta.request_id,
+            deadline = ?call.meta.deadline,
+            "Dropping call past its deadline"
+        );
+        metrics::expired_call(stream);
+    }
+
+    fn decode(delivery: &TransportDelivery) -> PackerResult<Option<Delivery<TCall>>> {
+        let payload: ManagerMessage<TCall, TResponse> =
+            ManagerMessage::unpack::<TPacker>(&delivery.message)?;
+        match payload.payload {
+            ManagerMessagePayload::Call(manager_call) => {
+                let disc = manager_call.discriminant();
+                tracing::trace!("recv call {}: {}", delivery.offset, disc);
+                Ok(Some(Delivery::new(
+                    delivery.offset,
+                    payload.meta,
+                    manager_call,
+                )))
+            }
+            ManagerMessagePayload::Response(manager_response) => {
+                let disc = manager_response.discriminant();
+                tracing::trace!("ignore recv'd response {}: {}", delivery.offset, disc);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<TCall: MessageQueuePayload, TResponse: MessageQueuePayload, TPacker: Packer> Stream
+    for MessageQueueServer<TCall, TResponse, TPacker>
+{
+    type Item = MessageQueueServerResult<Delivery<TCall>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Coming back for more means the previous call has been dealt with
+        self.acknowledge();
+
+        if let Some(e) = self.deferred.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        let server = &mut *self;
+        Self::poll_call(&mut server.deliveries, &mut server.unacknowledged, cx)
+    }
+}
+
+impl<TCall: MessageQueuePayload, TResponse: MessageQueuePayload, TPacker: Packer> Drop
+    for MessageQueueServer<TCall, TResponse, TPacker>
+{
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        channel::ChannelConfigurationBuilder, client::MessageQueueClient, offset::StartPosition,
+        pack::JsonPacker, transport::InMemoryTransport,
+    };
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Job(u32);
+    impl MessageQueuePayload for Job {
+        type Discriminant = &'static str;
+
+        fn discriminant(&self) -> Self::Discriminant {
+            "job"
+        }
+    }
+
+    type JobServer = MessageQueueServer<Job, Job, JsonPacker>;
+
+    fn jobs(calls: Vec<Delivery<Job>>) -> Vec<u32> {
+        calls.into_iter().map(|call| call.payload.0).collect()
+    }
+
+    #[tokio::test]
+    async fn single_active_consumer_fails_over() {
+        let config = ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("jobs")
+            .consumer_name("workers")
+            .single_active_consumer(true)
+            .start_position(StartPosition::First)
+            .build()
+            .unwrap();
+        let transport = InMemoryTransport::new();
+        let mut active = JobServer::with_transport("a".into(), &config, transport.clone())
+            .await
+            .unwrap();
+        let mut standby = JobServer::with_transport("b".into(), &config, transport.clone())
+            .await
+            .unwrap();
+        let client = MessageQueueClient::<Job, Job, JsonPacker>::with_transport(
+            "client".into(),
+            &config,
+            transport,
+        )
+        .await
+        .unwrap();
+
+        client.send(Job(1)).await.unwrap();
+        assert_eq!(jobs(active.recv().await.unwrap()), [1]);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), standby.recv())
+                .await
+                .is_err(),
+            "only the active member receives calls"
+        );
+
+        active.commit().await.unwrap();
+        drop(active);
+        client.send(Job(2)).await.unwrap();
+        assert_eq!(jobs(standby.recv().await.unwrap()), [2]);
+    }
+
+    #[tokio::test]
+    async fn expired_calls_are_dropped() {
+        let expired_calls =
+            || crate::metrics::tests::recorded("mq.server.expired_calls", "expiring.calls");
+        assert_eq!(expired_calls(), 0);
+
+        let config = ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("expiring")
+            .start_position(StartPosition::First)
+            .build()
+            .unwrap();
+        let transport = InMemoryTransport::new();
+        let mut server = JobServer::with_transport("server".into(), &config, transport.clone())
+            .await
+            .unwrap();
+        let client = |config| {
+            MessageQueueClient::<Job, Job, JsonPacker>::with_transport(
+                "client".into(),
+                config,
+                transport.clone(),
+            )
+        };
+        let mut late = config.clone();
+        late.call_ttl = Some(Duration::ZERO);
+        client(&late).await.unwrap().send(Job(1)).await.unwrap();
+        client(&config).await.unwrap().send(Job(2)).await.unwrap();
+
+        assert_eq!(jobs(server.recv().await.unwrap()), [2]);
+        assert_eq!(expired_calls(), 1);
     }
 }
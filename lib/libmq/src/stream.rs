@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use rabbitmq_stream_client::{
+    error::{ConsumerCreateError, StreamCreateError},
+    types::{ByteCapacity, OffsetSpecification, ResponseCode},
+    Consumer, Environment,
+};
+
+use crate::{
+    channel::ChannelConfiguration,
+    connection::Connection,
+    retention::{CreationPolicy, StreamSetupError},
+};
+
+pub(crate) async fn ensure_stream(
+    connection: &Connection,
+    config: &ChannelConfiguration,
+    stream: &str,
+    partitions: Option<usize>,
+) -> Result<(), StreamSetupError> {
+    let retention = &config.retention;
+    match config.creation_policy {
+        CreationPolicy::Never => return Ok(()),
+        CreationPolicy::CreateIfMissing => {}
+        CreationPolicy::RequireExisting => {
+            let streams = match partitions {
+                Some(partitions) => (0..partitions)
+                    .map(|partition| format!("{stream}-{partition}"))
+                    .collect(),
+                None => vec![stream.to_string()],
+            };
+            // Only creating tells whether the arguments match, and requiring is for callers
+            // that may not create
+            return require_existing(connection, stream, streams).await;
+        }
+    }
+
+    let mut creator = connection.environment().stream_creator();
+    if let Some(bytes) = retention.max_length_bytes {
+        creator = creator.max_length(ByteCapacity::B(bytes));
+    }
+    if let Some(bytes) = retention.max_segment_size_bytes {
+        creator = creator.max_segment_size(ByteCapacity::B(bytes));
+    }
+    if let Some(age) = retention.max_age {
+        creator = creator.max_age(age);
+    }
+
+    // Creating a stream that already exists is how the broker tells us whether its arguments
+    // match
+    let created = match partitions {
+        Some(partitions) => creator.create_super_stream(stream, partitions, None).await,
+        None => creator.create(stream).await,
+    };
+
+    match created {
+        Ok(()) => {
+            tracing::info!(stream, %retention, "Stream created");
+            Ok(())
+        }
+        Err(StreamCreateError::Create { status, .. }) => match status {
+            ResponseCode::StreamAlreadyExists => Ok(()),
+            ResponseCode::PrecoditionFailed => {
+                tracing::error!(stream, %retention, "Stream exists with different arguments");
+                Err(StreamSetupError::ArgumentsMismatch {
+                    stream: stream.to_string(),
+                    requested: retention.to_string(),
+                })
+            }
+            status => {
+                tracing::error!(stream, "Failed to create stream: {status:?}");
+                Err(StreamSetupError::Create {
+                    stream: stream.to_string(),
+                    reason: format!("{status:?}"),
+                })
+            }
+        },
+        Err(e) => {
+            tracing::error!(
+                stream,
+                error = e.to_string(),
+                "Failed to create stream: {e}"
+            );
+            Err(StreamSetupError::Create {
+                stream: stream.to_string(),
+                reason: e.to_string(),
+            })
+        }
+    }
+}
+
+async fn require_existing(
+    connection: &Connection,
+    stream: &str,
+    streams: Vec<String>,
+) -> Result<(), StreamSetupError> {
+    let create_error = |reason: String| StreamSetupError::Create {
+        stream: stream.to_string(),
+        reason,
+    };
+
+    let client = connection
+        .client()
+        .await
+        .map_err(|e| create_error(e.to_string()))?;
+    let metadata = client
+        .metadata(streams.clone())
+        .await
+        .map_err(|e| create_error(e.to_string()));
+    if let Err(e) = client.close().await {
+        tracing::warn!(stream, "Failed to close metadata client: {e}");
+    }
+    let metadata = metadata?;
+
+    match streams.into_iter().find(|stream| {
+        metadata
+            .get(stream)
+            .is_none_or(|metadata| metadata.response_code != ResponseCode::Ok)
+    }) {
+        Some(missing) => {
+            tracing::error!(stream = missing, "Required stream does not exist");
+            Err(StreamSetupError::Missing { stream: missing })
+        }
+        None => Ok(()),
+    }
+}
+
+pub(crate) async fn build_consumer(
+    environment: &Environment,
+    stream: &str,
+    super_stream: Option<&str>,
+    config: &ChannelConfiguration,
+    resume: Option<u64>,
+) -> Result<Consumer, ConsumerCreateError> {
+    let start = OffsetSpecification::from(&config.start_position);
+    let Some(name) = config.consumer_name.as_deref() else {
+        let start = resume.map(OffsetSpecification::Offset).unwrap_or(start);
+        return environment.consumer().offset(start).build(stream).await;
+    };
+
+    if config.single_active_consumer {
+        return build_single_active_consumer(
+            environment,
+            stream,
+            super_stream,
+            name,
+            start,
+            resume,
+        )
+        .await;
+    }
+
+    if let Some(resume) = resume {
+        tracing::info!(
+            stream,
+            consumer = name,
+            offset = resume,
+            "Resuming consumer"
+        );
+        return environment
+            .consumer()
+            .name(name)
+            .offset(OffsetSpecification::Offset(resume))
+            .build(stream)
+            .await;
+    }
+
+    let consumer = environment
+        .consumer()
+        .name(name)
+        .offset(start)
+        .build(stream)
+        .await?;
+
+    // The stored offset can only be queried through a named subscription, so resubscribe
+    // from just after it once we know where that is
+    let stored = match consumer.query_offset().await {
+        Ok(stored) => stored,
+        Err(e) => {
+            tracing::info!(
+                stream,
+                consumer = name,
+                "No stored offset ({e}), starting from {:?}",
+                config.start_position
+            );
+            return Ok(consumer);
+        }
+    };
+    if let Err(e) = consumer.handle().close().await {
+        tracing::warn!(
+            stream,
+            consumer = name,
+            "Failed to close probe consumer: {e}"
+        );
+    }
+
+    tracing::info!(
+        stream,
+        consumer = name,
+        offset = stored,
+        "Resuming after stored offset"
+    );
+    environment
+        .consumer()
+        .name(name)
+        .offset(OffsetSpecification::Offset(stored + 1))
+        .build(stream)
+        .await
+}
+
+// The broker asks whichever member becomes active where to start, so resuming from the
+// stored offset happens there rather than up front
+async fn build_single_active_consumer(
+    environment: &Environment,
+    stream: &str,
+    super_stream: Option<&str>,
+    name: &str,
+    start: OffsetSpecification,
+    resume: Option<u64>,
+) -> Result<Consumer, ConsumerCreateError> {
+    let properties = super_stream
+        .map(|super_stream| HashMap::from([("super-stream".to_string(), super_stream.to_string())]))
+        .unwrap_or_default();
+
+    environment
+        .consumer()
+        .name(name)
+        .properties(properties)
+        .offset(start.clone())
+        .enable_single_active_consumer(true)
+        .consumer_update(move |active, context| {
+            let start = start.clone();
+            async move {
+                let (name, stream) = (context.name(), context.stream());
+                let active = active == 1;
+                tracing::info!(
+                    stream,
+                    consumer = name,
+                    active,
+                    "Single active consumer updated"
+                );
+
+                // Another member may have got further while this one was away
+                match (context.client().query_offset(name, &stream).await, resume) {
+                    (Ok(stored), resume) => {
+                        OffsetSpecification::Offset(resume.unwrap_or_default().max(stored + 1))
+                    }
+                    (Err(_), Some(resume)) => OffsetSpecification::Offset(resume),
+                    (Err(_), None) => start,
+                }
+            }
+        })
+        .build(stream)
+        .await
+}
@@ -0,0 +1,887 @@
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
+use liberror::AnyError;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rabbitmq_stream_client::types::Message;
+use rabbitmq_stream_protocol::codec::{Decoder, Encoder};
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::{
+    channel::ChannelConfiguration,
+    connection::ConnectionStatus,
+    offset::StartPosition,
+    pack::PackOptions,
+    retention::{CreationPolicy, Retention, StreamSetupError},
+};
+
+use super::{
+    resume_offset, Pack, PublishError, Transport, TransportConsumer, TransportDelivery,
+    TransportProducer,
+};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Same as RabbitMQ's default
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 500_000_000;
+/// Length and CRC32 of the encoded message, both big endian `u32`
+const FRAME_HEADER_SIZE: usize = 8;
+/// Position of the frame in its segment and when it was written in milliseconds, both big endian
+const INDEX_ENTRY_SIZE: u64 = 16;
+const RETENTION_FILE: &str = "retention";
+const LOCK_FILE: &str = ".lock";
+
+/// Names end up as file names, so keep them to one path component
+const NAME: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_');
+
+#[derive(Debug, Error)]
+enum FileLogError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Stream \"{0}\" does not exist")]
+    MissingStream(String),
+    #[error("Consumer has no name to store offsets under")]
+    Unnamed,
+    #[error("Failed to encode message: {0}")]
+    Encode(String),
+    #[error("Frame {offset} of \"{stream}\" is corrupt: {reason}")]
+    Corrupt {
+        stream: String,
+        offset: u64,
+        reason: String,
+    },
+}
+
+/// Streams kept as segmented logs in a local directory, for running without a broker. Any number
+/// of processes on the host can share the directory, producers take turns through a file lock and
+/// consumers tail the logs. Frames are messages exactly as the `Packer` built them.
+#[derive(Clone)]
+pub struct FileTransport {
+    root: PathBuf,
+    poll_interval: Duration,
+    status: Arc<watch::Sender<ConnectionStatus>>,
+}
+impl FileTransport {
+    /// Keep streams under `root`, creating it if needed
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            status: Arc::new(watch::Sender::new(ConnectionStatus::Connected)),
+        })
+    }
+
+    /// How often consumers that have caught up look for new frames
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    fn log(&self, stream: &str) -> StreamLog {
+        StreamLog::new(&self.root, stream)
+    }
+}
+
+impl Transport for FileTransport {
+    fn ensure_stream<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        partitions: Option<usize>,
+    ) -> BoxFuture<'a, Result<(), StreamSetupError>> {
+        async move {
+            if config.creation_policy == CreationPolicy::Never {
+                return Ok(());
+            }
+            let streams = match partitions {
+                Some(partitions) => (0..partitions)
+                    .map(|partition| format!("{stream}-{partition}"))
+                    .collect(),
+                None => vec![stream.to_string()],
+            };
+
+            let transport = self.clone();
+            let creation_policy = config.creation_policy;
+            let retention = config.retention.clone();
+            unblock(move || {
+                for name in streams {
+                    let log = transport.log(&name);
+                    let create_error = |e: io::Error| StreamSetupError::Create {
+                        stream: name.clone(),
+                        reason: e.to_string(),
+                    };
+
+                    if !log.exists() {
+                        if creation_policy == CreationPolicy::RequireExisting {
+                            tracing::error!(stream = name, "Required stream does not exist");
+                            return Err(StreamSetupError::Missing { stream: name });
+                        }
+                        if log.create(&retention).map_err(create_error)? {
+                            tracing::info!(stream = name, %retention, "Stream created");
+                        }
+                    }
+
+                    if log.retention().map_err(create_error)? != retention.to_string() {
+                        tracing::error!(stream = name, %retention, "Stream exists with different arguments");
+                        return Err(StreamSetupError::ArgumentsMismatch {
+                            stream: name,
+                            requested: retention.to_string(),
+                        });
+                    }
+                }
+                Ok(())
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn producer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportProducer>, AnyError>> {
+        async move {
+            let log = self.log(stream);
+            if !unblock({
+                let log = log.clone();
+                move || log.exists()
+            })
+            .await
+            {
+                return Err(FileLogError::MissingStream(stream.to_string()).into());
+            }
+
+            Ok(Box::new(FileProducer {
+                log,
+                retention: config.retention.clone(),
+                name: super::producer_name(config)?.map(str::to_string),
+                next_publishing_id: AtomicU64::new(1),
+            }) as Box<dyn TransportProducer>)
+        }
+        .boxed()
+    }
+
+    fn consumer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        _super_stream: Option<&'a str>,
+        resume: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportConsumer>, AnyError>> {
+        async move {
+            let log = self.log(stream);
+            let name = config.consumer_name.clone();
+            let mut cursor = Cursor {
+                log: log.clone(),
+                poll_interval: self.poll_interval,
+                name: name.clone(),
+                start: config.start_position.clone(),
+                resume,
+                single_active: name.is_some() && config.single_active_consumer,
+                active: None,
+                next: None,
+                segment: None,
+            };
+            let cursor = unblock(move || {
+                if !cursor.log.exists() {
+                    return Err(FileLogError::MissingStream(cursor.log.stream.clone()));
+                }
+                // Decide where to start now, unless waiting for a turn in a group
+                cursor.activate()?;
+                Ok(cursor)
+            })
+            .await?;
+
+            Ok(Box::new(FileConsumer {
+                log,
+                name,
+                deliveries: Mutex::new(cursor.deliveries()),
+            }) as Box<dyn TransportConsumer>)
+        }
+        .boxed()
+    }
+
+    fn status(&self) -> ConnectionStatus {
+        *self.status.borrow()
+    }
+
+    fn status_changes(&self) -> watch::Receiver<ConnectionStatus> {
+        self.status.subscribe()
+    }
+}
+
+/// A stream's directory. Frames are kept in segment files named after the offset of their first
+/// frame, each with an index of where its frames are and when they were written. Stored offsets,
+/// producer sequences and single active consumer locks live in subdirectories.
+#[derive(Clone)]
+struct StreamLog {
+    stream: String,
+    path: PathBuf,
+}
+impl StreamLog {
+    fn new(root: &Path, stream: &str) -> Self {
+        Self {
+            stream: stream.to_string(),
+            path: root.join(file_name(stream)),
+        }
+    }
+
+    fn exists(&self) -> bool {
+        self.path.join(RETENTION_FILE).exists()
+    }
+
+    /// Create the stream, unless another process beat us to it
+    fn create(&self, retention: &Retention) -> io::Result<bool> {
+        for directory in ["offsets", "producers", "consumers"] {
+            fs::create_dir_all(self.path.join(directory))?;
+        }
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path.join(RETENTION_FILE))
+        {
+            Ok(mut file) => {
+                file.write_all(retention.to_string().as_bytes())?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn retention(&self) -> io::Result<String> {
+        fs::read_to_string(self.path.join(RETENTION_FILE))
+    }
+
+    fn segment(&self, base: u64, extension: &str) -> PathBuf {
+        self.path.join(format!("{base:020}.{extension}"))
+    }
+
+    /// Base offsets of the segments still on disk, oldest first
+    fn segments(&self) -> io::Result<Vec<u64>> {
+        let mut segments = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "log") {
+                if let Some(base) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok())
+                {
+                    segments.push(base);
+                }
+            }
+        }
+        segments.sort_unstable();
+        Ok(segments)
+    }
+
+    /// Number of frames indexed in the segment starting at `base`
+    fn frames(&self, base: u64) -> io::Result<u64> {
+        match fs::metadata(self.segment(base, "idx")) {
+            Ok(metadata) => Ok(metadata.len() / INDEX_ENTRY_SIZE),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Offset of the oldest frame still retained, and the offset the next frame will get
+    fn bounds(&self) -> io::Result<(u64, u64)> {
+        let segments = self.segments()?;
+        match (segments.first(), segments.last()) {
+            (Some(first), Some(last)) => Ok((*first, last + self.frames(*last)?)),
+            _ => Ok((0, 0)),
+        }
+    }
+
+    /// Offset of the first frame written at or after `timestamp`
+    fn offset_at(&self, timestamp: DateTime<Utc>) -> io::Result<Option<u64>> {
+        let timestamp = timestamp.timestamp_millis();
+        for base in self.segments()? {
+            let index = match fs::read(self.segment(base, "idx")) {
+                Ok(index) => index,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let found = index
+                .chunks_exact(INDEX_ENTRY_SIZE as usize)
+                .position(|entry| i64::from_be_bytes(entry[8..].try_into().unwrap()) >= timestamp);
+            if let Some(position) = found {
+                return Ok(Some(base + position as u64));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Held while appending, so producers in different processes take turns
+    fn lock(&self) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.join(LOCK_FILE))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// Append `message`, returning its offset. A deduplicating producer passes its name and the
+    /// message's publishing id, and the message is dropped if the id has been seen before.
+    fn append(
+        &self,
+        message: &Message,
+        publishing: Option<(&str, u64)>,
+        retention: &Retention,
+        sync: bool,
+    ) -> Result<Option<u64>, FileLogError> {
+        let mut frame = vec![0; FRAME_HEADER_SIZE];
+        message
+            .encode(&mut frame)
+            .map_err(|e| FileLogError::Encode(format!("{e:?}")))?;
+        let length = (frame.len() - FRAME_HEADER_SIZE) as u32;
+        let checksum = crc32fast::hash(&frame[FRAME_HEADER_SIZE..]);
+        frame[..4].copy_from_slice(&length.to_be_bytes());
+        frame[4..FRAME_HEADER_SIZE].copy_from_slice(&checksum.to_be_bytes());
+
+        let _lock = self.lock()?;
+        if let Some((name, publishing_id)) = publishing {
+            // Same as the broker, anything at or below the last id seen is a retry
+            if self
+                .producer_sequence(name)?
+                .is_some_and(|sequence| publishing_id <= sequence)
+            {
+                tracing::debug!(
+                    stream = self.stream,
+                    publishing_id,
+                    "Dropping duplicate publish"
+                );
+                return Ok(None);
+            }
+        }
+
+        let mut segments = self.segments()?;
+        let mut base = segments.last().copied().unwrap_or_default();
+        let mut frames = self.frames(base)?;
+        let max_segment_size = retention
+            .max_segment_size_bytes
+            .unwrap_or(DEFAULT_MAX_SEGMENT_SIZE);
+        let segment_size = match fs::metadata(self.segment(base, "log")) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if segment_size >= max_segment_size && frames > 0 {
+            base += frames;
+            frames = 0;
+            segments.push(base);
+            self.enforce(retention, &segments)?;
+        }
+
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.segment(base, "log"))?;
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.segment(base, "idx"))?;
+        // Drop whatever a writer that died halfway through an entry left behind
+        index.set_len(frames * INDEX_ENTRY_SIZE)?;
+
+        // Frames only count once indexed, so a frame without an entry is simply skipped over
+        let position = log.metadata()?.len();
+        log.write_all(&frame)?;
+        let mut entry = [0; INDEX_ENTRY_SIZE as usize];
+        entry[..8].copy_from_slice(&position.to_be_bytes());
+        entry[8..].copy_from_slice(&Utc::now().timestamp_millis().to_be_bytes());
+        index.write_all(&entry)?;
+        if sync {
+            log.sync_data()?;
+            index.sync_data()?;
+        }
+
+        if let Some((name, publishing_id)) = publishing {
+            write_replacing(
+                &self.path.join("producers").join(file_name(name)),
+                publishing_id,
+            )?;
+        }
+        Ok(Some(base + frames))
+    }
+
+    /// Drop the oldest segments while the stream is over its retention, never the one being
+    /// written to. Consumers still reading a dropped segment keep their handle on it.
+    fn enforce(&self, retention: &Retention, segments: &[u64]) -> io::Result<()> {
+        let mut size = 0;
+        for base in segments {
+            if let Ok(metadata) = fs::metadata(self.segment(*base, "log")) {
+                size += metadata.len();
+            }
+        }
+
+        for base in &segments[..segments.len().saturating_sub(1)] {
+            let metadata = fs::metadata(self.segment(*base, "log"))?;
+            let too_big = retention
+                .max_length_bytes
+                .is_some_and(|max_length| size > max_length);
+            let too_old = retention.max_age.is_some_and(|max_age| {
+                metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age > max_age)
+            });
+            if !too_big && !too_old {
+                break;
+            }
+
+            fs::remove_file(self.segment(*base, "log"))?;
+            fs::remove_file(self.segment(*base, "idx"))?;
+            size -= metadata.len();
+            tracing::debug!(stream = self.stream, base, "Dropped segment");
+        }
+        Ok(())
+    }
+
+    fn producer_sequence(&self, name: &str) -> io::Result<Option<u64>> {
+        read_number(&self.path.join("producers").join(file_name(name)))
+    }
+
+    fn stored_offset(&self, name: &str) -> io::Result<Option<u64>> {
+        read_number(&self.path.join("offsets").join(file_name(name)))
+    }
+
+    fn store_offset(&self, name: &str, offset: u64) -> io::Result<()> {
+        write_replacing(&self.path.join("offsets").join(file_name(name)), offset)
+    }
+
+    /// The lock making the holder the active member of group `name`, if nobody else holds it
+    fn try_lock_group(&self, name: &str) -> io::Result<Option<File>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(
+                self.path
+                    .join("consumers")
+                    .join(format!("{}.lock", file_name(name))),
+            )?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(file)),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+/// Run `work` on the blocking pool, file I/O and locks would hold up the runtime otherwise
+async fn unblock<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(done) => done,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+fn file_name(name: &str) -> String {
+    utf8_percent_encode(name, NAME).to_string()
+}
+
+fn read_number(path: &Path) -> io::Result<Option<u64>> {
+    match fs::read_to_string(path) {
+        Ok(number) => number
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write through a temporary file, so readers never see half a number
+fn write_replacing(path: &Path, number: u64) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, number.to_string())?;
+    fs::rename(temporary, path)
+}
+
+struct FileProducer {
+    log: StreamLog,
+    retention: Retention,
+    /// Set when deduplicating
+    name: Option<String>,
+    next_publishing_id: AtomicU64,
+}
+impl TransportProducer for FileProducer {
+    /// Confirming a message waits for it to be synced to disk
+    fn send<'a>(
+        &'a self,
+        pack: Pack<'a>,
+        confirm: bool,
+    ) -> BoxFuture<'a, Result<(), PublishError>> {
+        async move {
+            let publishing_id = match &self.name {
+                Some(name) => {
+                    // Carry on from the last id stored under the name, wherever it came from
+                    let sequence = unblock({
+                        let (log, name) = (self.log.clone(), name.clone());
+                        move || log.producer_sequence(&name)
+                    })
+                    .await
+                    .map_err(|e| PublishError::Publish(e.into()))?
+                    .unwrap_or_default();
+                    self.next_publishing_id
+                        .fetch_max(sequence + 1, Ordering::SeqCst);
+                    Some(self.next_publishing_id.fetch_add(1, Ordering::SeqCst))
+                }
+                None => None,
+            };
+            let message = pack(&PackOptions {
+                publishing_id,
+                ..PackOptions::default()
+            })
+            .map_err(PublishError::Pack)?;
+
+            let (log, retention) = (self.log.clone(), self.retention.clone());
+            let publishing = self.name.clone().zip(publishing_id);
+            unblock(move || {
+                let publishing = publishing
+                    .as_ref()
+                    .map(|(name, publishing_id)| (name.as_str(), *publishing_id));
+                log.append(&message, publishing, &retention, confirm)
+            })
+            .await
+            .map_err(|e| PublishError::Publish(e.into()))?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// An open segment, read frame by frame
+struct Segment {
+    base: u64,
+    log: File,
+    index: File,
+}
+impl Segment {
+    fn open(log: &StreamLog, base: u64) -> io::Result<Self> {
+        Ok(Self {
+            base,
+            log: File::open(log.segment(base, "log"))?,
+            index: File::open(log.segment(base, "idx"))?,
+        })
+    }
+
+    /// The frame at `offset`, if it has been indexed yet
+    fn read(&mut self, stream: &str, offset: u64) -> Result<Option<Message>, FileLogError> {
+        let entry_at = (offset - self.base) * INDEX_ENTRY_SIZE;
+        if self.index.metadata()?.len() < entry_at + INDEX_ENTRY_SIZE {
+            return Ok(None);
+        }
+        let mut entry = [0; INDEX_ENTRY_SIZE as usize];
+        self.index.seek(SeekFrom::Start(entry_at))?;
+        self.index.read_exact(&mut entry)?;
+        let position = u64::from_be_bytes(entry[..8].try_into().unwrap());
+
+        let corrupt = |reason: String| FileLogError::Corrupt {
+            stream: stream.to_string(),
+            offset,
+            reason,
+        };
+        let mut header = [0; FRAME_HEADER_SIZE];
+        self.log.seek(SeekFrom::Start(position))?;
+        self.log.read_exact(&mut header)?;
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let checksum = u32::from_be_bytes(header[4..].try_into().unwrap());
+        // A torn or corrupt header could claim gigabytes, don't allocate more than is there
+        let remaining = self
+            .log
+            .metadata()?
+            .len()
+            .saturating_sub(position + FRAME_HEADER_SIZE as u64);
+        if u64::from(length) > remaining {
+            return Err(corrupt(format!(
+                "frame of {length} bytes with {remaining} left in the segment"
+            )));
+        }
+        let mut frame = vec![0; length as usize];
+        self.log.read_exact(&mut frame)?;
+        if crc32fast::hash(&frame) != checksum {
+            return Err(corrupt("checksum mismatch".to_string()));
+        }
+
+        let (_, message) = Message::decode(&frame).map_err(|e| corrupt(format!("{e:?}")))?;
+        Ok(Some(message))
+    }
+}
+
+enum Lookup {
+    Frame(Message),
+    /// Nothing at the offset yet
+    Pending,
+    /// The offset has been dropped by retention, the oldest frame left is at this one
+    Truncated(u64),
+}
+
+/// Where a consumer has got to in its stream
+struct Cursor {
+    log: StreamLog,
+    poll_interval: Duration,
+    name: Option<String>,
+    start: StartPosition,
+    resume: Option<u64>,
+    single_active: bool,
+    /// Held while this consumer is the active member of its group, released when it's dropped
+    active: Option<File>,
+    /// Offset of the next delivery, decided on activation
+    next: Option<u64>,
+    segment: Option<Segment>,
+}
+impl Cursor {
+    fn deliveries(self) -> BoxStream<'static, Result<TransportDelivery, AnyError>> {
+        stream::unfold(self, |cursor| async move {
+            let (cursor, delivery) = cursor.next_delivery().await;
+            if delivery.is_err() {
+                // Don't spin on a stream that keeps failing
+                tokio::time::sleep(cursor.poll_interval).await;
+            }
+            Some((delivery.map_err(AnyError::from), cursor))
+        })
+        .boxed()
+    }
+
+    /// Polls on the blocking pool, so the cursor goes there and back each time
+    async fn next_delivery(mut self) -> (Self, Result<TransportDelivery, FileLogError>) {
+        loop {
+            let (cursor, polled) = unblock(move || {
+                let polled = self.poll();
+                (self, polled)
+            })
+            .await;
+            self = cursor;
+            match polled {
+                Ok(Some(delivery)) => return (self, Ok(delivery)),
+                Ok(None) => tokio::time::sleep(self.poll_interval).await,
+                Err(e) => return (self, Err(e)),
+            }
+        }
+    }
+
+    /// Decide where to start, once it's this consumer's turn
+    fn activate(&mut self) -> io::Result<bool> {
+        if self.next.is_some() {
+            return Ok(true);
+        }
+        if self.single_active && self.active.is_none() {
+            let name = self.name.as_deref().unwrap_or_default();
+            match self.log.try_lock_group(name)? {
+                Some(lock) => self.active = Some(lock),
+                None => return Ok(false),
+            }
+        }
+
+        let offset = self.start_offset()?;
+        tracing::info!(
+            stream = self.log.stream,
+            consumer = self.name,
+            offset,
+            "File consumer active"
+        );
+        self.next = Some(offset);
+        Ok(true)
+    }
+
+    fn start_offset(&self) -> io::Result<u64> {
+        let stored = match &self.name {
+            Some(name) => self.log.stored_offset(name)?,
+            None => None,
+        };
+        if let Some(offset) = resume_offset(self.resume, stored, self.single_active) {
+            return Ok(offset);
+        }
+
+        let (first, next) = self.log.bounds()?;
+        Ok(match &self.start {
+            StartPosition::First => first,
+            StartPosition::Last => next.saturating_sub(1).max(first),
+            StartPosition::Next => next,
+            StartPosition::Offset(offset) => *offset,
+            StartPosition::Timestamp(timestamp) => self.log.offset_at(*timestamp)?.unwrap_or(next),
+        })
+    }
+
+    fn poll(&mut self) -> Result<Option<TransportDelivery>, FileLogError> {
+        if !self.activate()? {
+            return Ok(None);
+        }
+        let Some(mut offset) = self.next else {
+            return Ok(None);
+        };
+
+        loop {
+            match self.read(offset) {
+                Ok(Lookup::Frame(message)) => {
+                    self.next = Some(offset + 1);
+                    return Ok(Some(TransportDelivery { offset, message }));
+                }
+                Ok(Lookup::Pending) => return Ok(None),
+                Ok(Lookup::Truncated(first)) => {
+                    tracing::warn!(
+                        stream = self.log.stream,
+                        offset,
+                        first,
+                        "Offset dropped by retention, skipping ahead"
+                    );
+                    offset = first;
+                    self.next = Some(first);
+                }
+                // Step over it, or the consumer is stuck there for good
+                Err(e @ FileLogError::Corrupt { .. }) => {
+                    self.next = Some(offset + 1);
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read(&mut self, offset: u64) -> Result<Lookup, FileLogError> {
+        loop {
+            let segment = match &mut self.segment {
+                Some(segment) if segment.base <= offset => segment,
+                _ => {
+                    let segments = self.log.segments()?;
+                    let Some(base) = segments.iter().rev().find(|base| **base <= offset) else {
+                        return Ok(match segments.first() {
+                            Some(first) => Lookup::Truncated(*first),
+                            None => Lookup::Pending,
+                        });
+                    };
+                    self.segment.insert(Segment::open(&self.log, *base)?)
+                }
+            };
+
+            if let Some(message) = segment.read(&self.log.stream, offset)? {
+                return Ok(Lookup::Frame(message));
+            }
+            // The writer may have moved on to a segment starting right here
+            if segment.base != offset && self.log.segment(offset, "log").exists() {
+                self.segment = None;
+                continue;
+            }
+            return Ok(Lookup::Pending);
+        }
+    }
+}
+
+struct FileConsumer {
+    log: StreamLog,
+    name: Option<String>,
+    /// Only ever polled through `&mut`, the mutex is there to make the consumer `Sync`
+    deliveries: Mutex<BoxStream<'static, Result<TransportDelivery, AnyError>>>,
+}
+impl Stream for FileConsumer {
+    type Item = Result<TransportDelivery, AnyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.deliveries
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .poll_next_unpin(cx)
+    }
+}
+impl TransportConsumer for FileConsumer {
+    fn store_offset(&self, offset: u64) -> BoxFuture<'_, Result<(), AnyError>> {
+        async move {
+            let Some(name) = self.name.clone() else {
+                return Err(FileLogError::Unnamed.into());
+            };
+            let log = self.log.clone();
+            unblock(move || log.store_offset(&name, offset)).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::ChannelConfigurationBuilder;
+
+    #[tokio::test]
+    async fn consumers_read_what_producers_append() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let transport = FileTransport::open(&root)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(5));
+        let config = ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("file")
+            .start_position(StartPosition::First)
+            .build()
+            .unwrap();
+        transport.ensure_stream(&config, "s", None).await.unwrap();
+
+        let producer = transport.producer(&config, "s").await.unwrap();
+        for body in ["one", "two"] {
+            let message = Message::builder().body(body.as_bytes().to_vec()).build();
+            assert!(producer.send(Box::new(|_| Ok(message)), true).await.is_ok());
+        }
+
+        let mut consumer = transport.consumer(&config, "s", None, None).await.unwrap();
+        for (offset, body) in [(0, "one"), (1, "two")] {
+            let delivery = consumer.next().await.unwrap().unwrap();
+            assert_eq!(delivery.offset, offset);
+            assert_eq!(delivery.message.data(), Some(body.as_bytes()));
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_longer_than_the_segment() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let transport = FileTransport::open(&root).unwrap();
+        let config = ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("file")
+            .build()
+            .unwrap();
+        transport.ensure_stream(&config, "s", None).await.unwrap();
+        let producer = transport.producer(&config, "s").await.unwrap();
+        let message = Message::builder().body(b"one".to_vec()).build();
+        assert!(producer.send(Box::new(|_| Ok(message)), true).await.is_ok());
+
+        let log = transport.log("s");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(log.segment(0, "log"))
+            .unwrap();
+        file.write_all(&u32::MAX.to_be_bytes()).unwrap();
+        let mut segment = Segment::open(&log, 0).unwrap();
+        assert!(matches!(
+            segment.read("s", 0),
+            Err(FileLogError::Corrupt { offset: 0, reason, .. }) if reason.contains("left in the segment")
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
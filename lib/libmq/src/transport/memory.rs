@@ -0,0 +1,538 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
+use liberror::AnyError;
+use rabbitmq_stream_client::types::Message;
+use thiserror::Error;
+use tokio::{sync::watch, time::Instant};
+
+use crate::{
+    channel::ChannelConfiguration,
+    connection::ConnectionStatus,
+    offset::StartPosition,
+    pack::PackOptions,
+    retention::{CreationPolicy, Retention, StreamSetupError},
+};
+
+use super::{
+    resume_offset, Pack, PublishError, Transport, TransportConsumer, TransportDelivery,
+    TransportProducer,
+};
+
+#[derive(Debug, Error)]
+enum InMemoryError {
+    #[error("Stream \"{0}\" does not exist")]
+    MissingStream(String),
+    #[error("Consumer has no name to store offsets under")]
+    Unnamed,
+}
+
+struct Entry {
+    message: Message,
+    published_at: DateTime<Utc>,
+    /// When consumers get to see the entry, after the transport's latency
+    visible_at: Instant,
+}
+
+struct Log {
+    retention: Retention,
+    entries: Vec<Entry>,
+}
+
+/// Keyed by consumer or producer name, then stream
+type NameAndStream = (String, String);
+
+#[derive(Default)]
+struct State {
+    streams: HashMap<String, Log>,
+    /// Stored offsets of named consumers
+    offsets: HashMap<NameAndStream, u64>,
+    /// Last publishing id seen from each deduplicating producer
+    sequences: HashMap<NameAndStream, u64>,
+    /// Members of single active consumer groups, the first one being active
+    groups: HashMap<NameAndStream, Vec<u64>>,
+    next_consumer_id: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Bumped whenever an entry is appended or a group changes hands
+    changed: watch::Sender<()>,
+    status: watch::Sender<ConnectionStatus>,
+    latency: Duration,
+}
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Streams held in process memory, for running clients and servers against each other without a
+/// broker. Clones share the same streams. Nothing is ever discarded, retention is only compared
+/// against streams that already exist.
+#[derive(Clone)]
+pub struct InMemoryTransport {
+    shared: Arc<Shared>,
+}
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold every message back from consumers for `latency` after it is published
+    pub fn with_latency(latency: Duration) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Default::default(),
+                changed: watch::Sender::new(()),
+                status: watch::Sender::new(ConnectionStatus::Connected),
+                latency,
+            }),
+        }
+    }
+
+    /// Number of messages published to `stream`
+    pub fn len(&self, stream: &str) -> usize {
+        self.shared
+            .state()
+            .streams
+            .get(stream)
+            .map_or(0, |log| log.entries.len())
+    }
+
+    /// Whether nothing has been published to `stream`
+    pub fn is_empty(&self, stream: &str) -> bool {
+        self.len(stream) == 0
+    }
+}
+impl Default for InMemoryTransport {
+    fn default() -> Self {
+        Self::with_latency(Duration::ZERO)
+    }
+}
+
+impl Transport for InMemoryTransport {
+    fn ensure_stream<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        partitions: Option<usize>,
+    ) -> BoxFuture<'a, Result<(), StreamSetupError>> {
+        async move {
+            if config.creation_policy == CreationPolicy::Never {
+                return Ok(());
+            }
+            let streams = match partitions {
+                Some(partitions) => (0..partitions)
+                    .map(|partition| format!("{stream}-{partition}"))
+                    .collect(),
+                None => vec![stream.to_string()],
+            };
+
+            let mut state = self.shared.state();
+            for name in streams {
+                match state.streams.get(&name) {
+                    Some(log) if log.retention != config.retention => {
+                        return Err(StreamSetupError::ArgumentsMismatch {
+                            stream: name,
+                            requested: config.retention.to_string(),
+                        });
+                    }
+                    Some(_) => {}
+                    None if config.creation_policy == CreationPolicy::RequireExisting => {
+                        return Err(StreamSetupError::Missing { stream: name });
+                    }
+                    None => {
+                        state.streams.insert(
+                            name,
+                            Log {
+                                retention: config.retention.clone(),
+                                entries: vec![],
+                            },
+                        );
+                    }
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn producer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportProducer>, AnyError>> {
+        async move {
+            if !self.shared.state().streams.contains_key(stream) {
+                return Err(InMemoryError::MissingStream(stream.to_string()).into());
+            }
+
+            Ok(Box::new(InMemoryProducer {
+                shared: self.shared.clone(),
+                stream: stream.to_string(),
+                name: super::producer_name(config)?.map(str::to_string),
+                next_publishing_id: AtomicU64::new(1),
+            }) as Box<dyn TransportProducer>)
+        }
+        .boxed()
+    }
+
+    fn consumer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        _super_stream: Option<&'a str>,
+        resume: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportConsumer>, AnyError>> {
+        async move {
+            let mut state = self.shared.state();
+            if !state.streams.contains_key(stream) {
+                return Err(InMemoryError::MissingStream(stream.to_string()).into());
+            }
+
+            let name = config.consumer_name.clone();
+            let id = state.next_consumer_id;
+            state.next_consumer_id += 1;
+            let group = name
+                .clone()
+                .filter(|_| config.single_active_consumer)
+                .map(|name| (name, stream.to_string()));
+            if let Some(group) = &group {
+                state.groups.entry(group.clone()).or_default().push(id);
+            }
+            let mut cursor = Cursor {
+                shared: self.shared.clone(),
+                changed: self.shared.changed.subscribe(),
+                stream: stream.to_string(),
+                name: name.clone(),
+                start: config.start_position.clone(),
+                resume,
+                group: group.clone().map(|group| (group, id)),
+                next: None,
+            };
+            // Members of a group waiting their turn only decide where to start once it comes
+            if cursor.is_active(&state) {
+                cursor.next = Some(cursor.start_offset(&state, &state.streams[stream]));
+            }
+            drop(state);
+
+            Ok(Box::new(InMemoryConsumer {
+                shared: self.shared.clone(),
+                stream: stream.to_string(),
+                name,
+                member: group.map(|group| (group, id)),
+                deliveries: Mutex::new(cursor.deliveries()),
+            }) as Box<dyn TransportConsumer>)
+        }
+        .boxed()
+    }
+
+    fn status(&self) -> ConnectionStatus {
+        *self.shared.status.borrow()
+    }
+
+    fn status_changes(&self) -> watch::Receiver<ConnectionStatus> {
+        self.shared.status.subscribe()
+    }
+}
+
+struct InMemoryProducer {
+    shared: Arc<Shared>,
+    stream: String,
+    /// Set when deduplicating
+    name: Option<String>,
+    next_publishing_id: AtomicU64,
+}
+impl TransportProducer for InMemoryProducer {
+    fn send<'a>(
+        &'a self,
+        pack: Pack<'a>,
+        confirm: bool,
+    ) -> BoxFuture<'a, Result<(), PublishError>> {
+        async move {
+            let publishing_id = self.name.as_ref().map(|name| {
+                // Carry on from the last id stored under the name, wherever it came from
+                let sequence = self
+                    .shared
+                    .state()
+                    .sequences
+                    .get(&(name.clone(), self.stream.clone()))
+                    .copied()
+                    .unwrap_or_default();
+                self.next_publishing_id
+                    .fetch_max(sequence + 1, Ordering::SeqCst);
+                self.next_publishing_id.fetch_add(1, Ordering::SeqCst)
+            });
+            let message = pack(&PackOptions {
+                publishing_id,
+                ..PackOptions::default()
+            })
+            .map_err(PublishError::Pack)?;
+
+            {
+                let mut state = self.shared.state();
+                if let (Some(name), Some(publishing_id)) = (&self.name, publishing_id) {
+                    let sequence = state
+                        .sequences
+                        .entry((name.clone(), self.stream.clone()))
+                        .or_default();
+                    // Same as the broker, anything at or below the last id seen is a retry
+                    if publishing_id <= *sequence {
+                        tracing::debug!(
+                            stream = self.stream,
+                            publishing_id,
+                            "Dropping duplicate publish"
+                        );
+                        return Ok(());
+                    }
+                    *sequence = publishing_id;
+                }
+
+                let Some(log) = state.streams.get_mut(&self.stream) else {
+                    return Err(PublishError::Publish(
+                        InMemoryError::MissingStream(self.stream.clone()).into(),
+                    ));
+                };
+                log.entries.push(Entry {
+                    message,
+                    published_at: Utc::now(),
+                    visible_at: Instant::now() + self.shared.latency,
+                });
+            }
+            self.shared.changed.send_replace(());
+
+            if confirm {
+                tokio::time::sleep(self.shared.latency).await;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// Where a consumer has got to in its stream
+struct Cursor {
+    shared: Arc<Shared>,
+    changed: watch::Receiver<()>,
+    stream: String,
+    name: Option<String>,
+    start: StartPosition,
+    resume: Option<u64>,
+    /// The single active consumer group the consumer is a member of, and its id in there
+    group: Option<(NameAndStream, u64)>,
+    /// Offset of the next delivery, decided on activation
+    next: Option<u64>,
+}
+
+enum Wait {
+    Changed,
+    Until(Instant),
+}
+
+impl Cursor {
+    fn deliveries(self) -> BoxStream<'static, Result<TransportDelivery, AnyError>> {
+        stream::unfold(self, |mut cursor| async move {
+            let delivery = cursor.next_delivery().await;
+            Some((Ok(delivery), cursor))
+        })
+        .boxed()
+    }
+
+    async fn next_delivery(&mut self) -> TransportDelivery {
+        loop {
+            // Anything that changes from here on wakes the wait below
+            self.changed.borrow_and_update();
+
+            let wait = {
+                let shared = self.shared.clone();
+                let state = shared.state();
+                self.poll(&state)
+            };
+            match wait {
+                Ok(delivery) => return delivery,
+                Err(Wait::Changed) => {
+                    let _ = self.changed.changed().await;
+                }
+                Err(Wait::Until(visible_at)) => {
+                    let _ = tokio::time::timeout_at(visible_at, self.changed.changed()).await;
+                }
+            }
+        }
+    }
+
+    fn poll(&mut self, state: &State) -> Result<TransportDelivery, Wait> {
+        if !self.is_active(state) {
+            self.next = None;
+            return Err(Wait::Changed);
+        }
+
+        let log = &state.streams[&self.stream];
+        let offset = match self.next {
+            Some(offset) => offset,
+            None => {
+                let offset = self.start_offset(state, log);
+                tracing::info!(
+                    stream = self.stream,
+                    consumer = self.name,
+                    offset,
+                    "In-memory consumer active"
+                );
+                self.next = Some(offset);
+                offset
+            }
+        };
+
+        match log.entries.get(offset as usize) {
+            Some(entry) if entry.visible_at <= Instant::now() => {
+                self.next = Some(offset + 1);
+                Ok(TransportDelivery {
+                    offset,
+                    message: entry.message.clone(),
+                })
+            }
+            Some(entry) => Err(Wait::Until(entry.visible_at)),
+            None => Err(Wait::Changed),
+        }
+    }
+
+    fn is_active(&self, state: &State) -> bool {
+        let Some((group, id)) = &self.group else {
+            return true;
+        };
+        state.groups.get(group).and_then(|members| members.first()) == Some(id)
+    }
+
+    fn start_offset(&self, state: &State, log: &Log) -> u64 {
+        let stored = self
+            .name
+            .as_ref()
+            .and_then(|name| state.offsets.get(&(name.clone(), self.stream.clone())))
+            .copied();
+        if let Some(offset) = resume_offset(self.resume, stored, self.group.is_some()) {
+            return offset;
+        }
+
+        let len = log.entries.len() as u64;
+        match &self.start {
+            StartPosition::First => 0,
+            StartPosition::Last => len.saturating_sub(1),
+            StartPosition::Next => len,
+            StartPosition::Offset(offset) => *offset,
+            StartPosition::Timestamp(timestamp) => log
+                .entries
+                .iter()
+                .position(|entry| entry.published_at >= *timestamp)
+                .map_or(len, |offset| offset as u64),
+        }
+    }
+}
+
+struct InMemoryConsumer {
+    shared: Arc<Shared>,
+    stream: String,
+    name: Option<String>,
+    member: Option<(NameAndStream, u64)>,
+    /// Only ever polled through `&mut`, the mutex is there to make the consumer `Sync`
+    deliveries: Mutex<BoxStream<'static, Result<TransportDelivery, AnyError>>>,
+}
+impl Stream for InMemoryConsumer {
+    type Item = Result<TransportDelivery, AnyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.deliveries
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .poll_next_unpin(cx)
+    }
+}
+impl TransportConsumer for InMemoryConsumer {
+    fn store_offset(&self, offset: u64) -> BoxFuture<'_, Result<(), AnyError>> {
+        async move {
+            let Some(name) = &self.name else {
+                return Err(InMemoryError::Unnamed.into());
+            };
+            self.shared
+                .state()
+                .offsets
+                .insert((name.clone(), self.stream.clone()), offset);
+            Ok(())
+        }
+        .boxed()
+    }
+}
+impl Drop for InMemoryConsumer {
+    /// Leave the group, handing over to the next member if this one was active
+    fn drop(&mut self) {
+        let Some((group, id)) = &self.member else {
+            return;
+        };
+        if let Some(members) = self.shared.state().groups.get_mut(group) {
+            members.retain(|member| member != id);
+        }
+        self.shared.changed.send_replace(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channel::ChannelConfigurationBuilder, transport::Pack};
+
+    fn stamped() -> Pack<'static> {
+        Box::new(|options| {
+            let builder = Message::builder().body(b"body".to_vec());
+            Ok(match options.publishing_id {
+                Some(publishing_id) => builder.publising_id(publishing_id),
+                None => builder,
+            }
+            .build())
+        })
+    }
+
+    fn publishing_ids(transport: &InMemoryTransport, stream: &str) -> Vec<Option<u64>> {
+        transport.shared.state().streams[stream]
+            .entries
+            .iter()
+            .map(|entry| entry.message.publishing_id().copied())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn deduplicating_producers_carry_on_from_the_last_id() {
+        let config = ChannelConfigurationBuilder::default()
+            .host("localhost")
+            .stream_name("dedup")
+            .deduplicate(true)
+            .producer_name("writer")
+            .build()
+            .unwrap();
+        let transport = InMemoryTransport::new();
+        transport.ensure_stream(&config, "s", None).await.unwrap();
+
+        // Built before the first one publishes, as a restarted replica's would be
+        let second = transport.producer(&config, "s").await.unwrap();
+        let first = transport.producer(&config, "s").await.unwrap();
+        for _ in 0..2 {
+            assert!(first.send(stamped(), true).await.is_ok());
+        }
+        drop(first);
+
+        assert!(second.send(stamped(), true).await.is_ok());
+        assert_eq!(publishing_ids(&transport, "s"), [Some(1), Some(2), Some(3)]);
+    }
+}
@@ -0,0 +1,180 @@
+use futures::{future::BoxFuture, FutureExt, Stream};
+use liberror::AnyError;
+use rabbitmq_stream_client::types::Message;
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::{
+    channel::ChannelConfiguration,
+    confirm::ConfirmHandle,
+    connection::ConnectionStatus,
+    pack::{PackOptions, PackerError, PackerResult},
+    retention::StreamSetupError,
+};
+
+mod file;
+mod memory;
+mod rabbitmq;
+
+pub use file::FileTransport;
+pub use memory::InMemoryTransport;
+pub use rabbitmq::RabbitMqTransport;
+
+/// Packs a message once the producer knows what to stamp on it
+pub type Pack<'a> = Box<dyn FnOnce(&PackOptions) -> PackerResult<Message> + Send + 'a>;
+
+pub enum PublishError {
+    Pack(PackerError),
+    Publish(AnyError),
+}
+
+/// What became of one message of a batch
+#[derive(Debug, Clone)]
+pub enum SendOutcome {
+    /// Published without waiting for the broker's confirm
+    Sent,
+    /// Stored by the broker
+    Confirmed,
+    /// Negatively confirmed, with the broker's response code
+    Rejected { code: u16 },
+    /// Failed to pack or to publish
+    Failed(AnyError),
+}
+impl SendOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Self::Sent | Self::Confirmed)
+    }
+
+    pub(crate) fn published(confirm: bool) -> Self {
+        if confirm {
+            Self::Confirmed
+        } else {
+            Self::Sent
+        }
+    }
+}
+impl From<PublishError> for SendOutcome {
+    fn from(value: PublishError) -> Self {
+        match value {
+            PublishError::Pack(e) => Self::Failed(e.into()),
+            PublishError::Publish(e) => Self::Failed(e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TransportDelivery {
+    pub offset: u64,
+    pub message: Message,
+}
+
+/// Where streams live, the client and server only reach them through one of these
+pub trait Transport: Send + Sync + 'static {
+    /// With `partitions`, `stream` is created as a super stream of that many partitions
+    fn ensure_stream<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        partitions: Option<usize>,
+    ) -> BoxFuture<'a, Result<(), StreamSetupError>>;
+
+    fn producer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportProducer>, AnyError>>;
+
+    /// `resume` wins over the channel's start position and stored offsets
+    fn consumer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        super_stream: Option<&'a str>,
+        resume: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportConsumer>, AnyError>>;
+
+    fn recover_consumer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        super_stream: Option<&'a str>,
+        resume: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportConsumer>, AnyError>> {
+        self.consumer(config, stream, super_stream, resume)
+    }
+
+    fn status(&self) -> ConnectionStatus;
+
+    fn status_changes(&self) -> watch::Receiver<ConnectionStatus>;
+}
+
+pub trait TransportProducer: Send + Sync {
+    fn send<'a>(&'a self, pack: Pack<'a>, confirm: bool)
+        -> BoxFuture<'a, Result<(), PublishError>>;
+
+    /// Returns once published, the handle resolves with the confirm. By default this waits for
+    /// the confirm itself.
+    fn send_tracked<'a>(
+        &'a self,
+        pack: Pack<'a>,
+    ) -> BoxFuture<'a, Result<ConfirmHandle, PublishError>> {
+        async move {
+            self.send(pack, true).await?;
+            Ok(ConfirmHandle::settled(SendOutcome::Confirmed))
+        }
+        .boxed()
+    }
+
+    /// Outcomes are in the order of `packs`. By default they go one at a time.
+    fn send_batch<'a>(
+        &'a self,
+        packs: Vec<Pack<'a>>,
+        confirm: bool,
+    ) -> BoxFuture<'a, Vec<SendOutcome>> {
+        async move {
+            let mut outcomes = Vec::with_capacity(packs.len());
+            for pack in packs {
+                outcomes.push(match self.send(pack, confirm).await {
+                    Ok(()) => SendOutcome::published(confirm),
+                    Err(e) => e.into(),
+                });
+            }
+            outcomes
+        }
+        .boxed()
+    }
+}
+
+/// Deliveries in offset order. The stream ending means the consumer has closed.
+pub trait TransportConsumer:
+    Stream<Item = Result<TransportDelivery, AnyError>> + Send + Sync + Unpin
+{
+    fn store_offset(&self, offset: u64) -> BoxFuture<'_, Result<(), AnyError>>;
+}
+
+#[derive(Debug, Error)]
+#[error("Deduplicating producer has no producer_name")]
+struct UnnamedProducer;
+
+pub(crate) fn producer_name(config: &ChannelConfiguration) -> Result<Option<&str>, AnyError> {
+    match (config.deduplicate, &config.producer_name) {
+        (false, _) => Ok(None),
+        (true, Some(name)) => Ok(Some(name)),
+        (true, None) => Err(UnnamedProducer.into()),
+    }
+}
+
+// A single active consumer takes the further of `resume` and `stored`, as another member
+// may have got further while it was away
+pub(crate) fn resume_offset(
+    resume: Option<u64>,
+    stored: Option<u64>,
+    single_active: bool,
+) -> Option<u64> {
+    let stored = stored.map(|stored| stored + 1);
+    match (resume, stored) {
+        (Some(resume), Some(stored)) if single_active => Some(resume.max(stored)),
+        (Some(resume), _) => Some(resume),
+        (None, stored) => stored,
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use liberror::AnyError;
+use rabbitmq_stream_client::Consumer;
+use tokio::sync::watch;
+
+use crate::{
+    channel::ChannelConfiguration,
+    connection::{Connection, ConnectionStatus},
+    producer::StreamProducer,
+    retention::StreamSetupError,
+    stream::{build_consumer, ensure_stream},
+};
+
+use super::{Transport, TransportConsumer, TransportDelivery, TransportProducer};
+
+/// RabbitMQ streams, over a connection that is rebuilt whenever the broker goes away
+#[derive(Clone)]
+pub struct RabbitMqTransport {
+    connection: Arc<Connection>,
+}
+impl RabbitMqTransport {
+    /// Connect to the first of the channel's hosts that answers
+    pub async fn connect(config: &ChannelConfiguration) -> Result<Self, AnyError> {
+        Ok(Self {
+            connection: Connection::connect(config).await?,
+        })
+    }
+}
+
+impl Transport for RabbitMqTransport {
+    fn ensure_stream<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        partitions: Option<usize>,
+    ) -> BoxFuture<'a, Result<(), StreamSetupError>> {
+        ensure_stream(&self.connection, config, stream, partitions).boxed()
+    }
+
+    fn producer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportProducer>, AnyError>> {
+        async move {
+            let producer = StreamProducer::build(&self.connection, config, stream).await?;
+            Ok(Box::new(producer) as Box<dyn TransportProducer>)
+        }
+        .boxed()
+    }
+
+    fn consumer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        super_stream: Option<&'a str>,
+        resume: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportConsumer>, AnyError>> {
+        async move {
+            let environment = self.connection.environment();
+            let consumer =
+                build_consumer(&environment, stream, super_stream, config, resume).await?;
+            Ok(Box::new(RabbitMqConsumer(consumer)) as Box<dyn TransportConsumer>)
+        }
+        .boxed()
+    }
+
+    /// Backs off and rebuilds the connection until the consumer can be built again
+    fn recover_consumer<'a>(
+        &'a self,
+        config: &'a ChannelConfiguration,
+        stream: &'a str,
+        super_stream: Option<&'a str>,
+        resume: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn TransportConsumer>, AnyError>> {
+        async move {
+            let consumer = self
+                .connection
+                .recover("consumer", |environment| async move {
+                    build_consumer(&environment, stream, super_stream, config, resume).await
+                })
+                .await?;
+            Ok(Box::new(RabbitMqConsumer(consumer)) as Box<dyn TransportConsumer>)
+        }
+        .boxed()
+    }
+
+    fn status(&self) -> ConnectionStatus {
+        self.connection.status()
+    }
+
+    fn status_changes(&self) -> watch::Receiver<ConnectionStatus> {
+        self.connection.status_changes()
+    }
+}
+
+struct RabbitMqConsumer(Consumer);
+impl Stream for RabbitMqConsumer {
+    type Item = Result<TransportDelivery, AnyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx).map(|delivery| {
+            delivery.map(|delivery| {
+                delivery
+                    .map(|delivery| TransportDelivery {
+                        offset: delivery.offset(),
+                        message: delivery.message().clone(),
+                    })
+                    .map_err(AnyError::from)
+            })
+        })
+    }
+}
+impl TransportConsumer for RabbitMqConsumer {
+    fn store_offset(&self, offset: u64) -> BoxFuture<'_, Result<(), AnyError>> {
+        async move { Ok(self.0.store_offset(offset).await?) }.boxed()
+    }
+}
@@ -1,4 +1,7 @@
-use libmq::payload::MessageQueuePayload;
+use libmq::{
+    meta::TraceContext,
+    payload::{MessageQueuePayload, UnknownVariant},
+};
 use libtran::Transaction;
 use serde::{Deserialize, Serialize};
 use strum::EnumDiscriminants;
@@ -14,6 +17,9 @@ pub enum CallPayload {
     Mul { lhs: f32, rhs: f32 },
     #[serde(rename = "dev.thmsn.sample.call.div")]
     Div { lhs: f32, rhs: f32 },
+    /// Sent by a newer client, answered with `ResponsePayload::Unsupported`
+    #[serde(untagged)]
+    Unknown(UnknownVariant),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,4 +34,16 @@ impl MessageQueuePayload for Call {
     fn discriminant(&self) -> Self::Discriminant {
         Self::Discriminant::from(&self.payload)
     }
+
+    fn trace_context(&self) -> Option<TraceContext> {
+        super::trace_context(&self.transaction)
+    }
+
+    /// Calls from the same source are answered in the order they were made
+    fn routing_key(&self) -> String {
+        self.transaction
+            .source()
+            .map(|source| source.to_string())
+            .unwrap_or_else(|| self.discriminant().to_string())
+    }
 }
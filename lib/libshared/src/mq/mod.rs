@@ -1,4 +1,10 @@
-use libmq::{nt_channel, pack::MessagePackPacker};
+use libmq::{
+    meta::TraceContext,
+    nt_channel,
+    pack::{JsonPacker, MessagePackPacker},
+    registry::Registry,
+};
+use libtran::Transaction;
 
 pub mod call;
 pub mod response;
@@ -11,5 +17,13 @@ nt_channel!(
     SampleServer,
     Call,
     Response,
-    MessagePackPacker
+    Registry<(MessagePackPacker, JsonPacker)>
 );
+
+/// The W3C trace context a transaction carries once it has been injected
+fn trace_context(transaction: &Transaction) -> Option<TraceContext> {
+    Some(TraceContext {
+        traceparent: transaction.cx.get("traceparent")?.clone(),
+        tracestate: transaction.cx.get("tracestate").cloned(),
+    })
+}
@@ -1,4 +1,7 @@
-use libmq::payload::MessageQueuePayload;
+use libmq::{
+    meta::TraceContext,
+    payload::{MessageQueuePayload, UnknownVariant},
+};
 use libtran::Transaction;
 use serde::{Deserialize, Serialize};
 use strum::EnumDiscriminants;
@@ -10,6 +13,12 @@ pub enum ResponsePayload {
     Result { result: f32 },
     #[serde(rename = "dev.thmsn.sample.response.too_big")]
     TooBig { lhs: f32, rhs: f32 },
+    /// The call was of a kind this listener doesn't know how to handle
+    #[serde(rename = "dev.thmsn.sample.response.unsupported")]
+    Unsupported { operation: String },
+    /// Sent by a newer listener
+    #[serde(untagged)]
+    Unknown(UnknownVariant),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,4 +46,8 @@ impl MessageQueuePayload for Response {
     fn discriminant(&self) -> Self::Discriminant {
         Self::Discriminant::from(&self.payload)
     }
+
+    fn trace_context(&self) -> Option<TraceContext> {
+        super::trace_context(&self.transaction)
+    }
 }
@@ -20,6 +20,10 @@ impl Transaction {
         self
     }
 
+    pub fn source(&self) -> Option<&str> {
+        self.cx.get("transaction.source").map(|s| s.as_str())
+    }
+
     /// Pull tran properties _out_ of the context
     pub fn inject(&mut self) {
         liblog::inject(self);
@@ -27,9 +31,7 @@ impl Transaction {
 
     /// Push tran properties _into_ the trace context
     pub fn extract(&mut self) -> &mut Self {
-        let this = liblog::extract(self);
-
-        this
+        liblog::extract(self)
     }
 }
 impl Injector for Transaction {
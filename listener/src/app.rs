@@ -1,3 +1,7 @@
+use libmq::{
+    connection::ConnectionStatus, delivery::Delivery, server::MessageQueueServerError,
+    transport::FileTransport,
+};
 use libshared::mq::{
     SampleServer,
     call::{Call, CallPayload},
@@ -18,42 +22,73 @@ pub struct App {
 impl App {
     pub async fn new(cancellation_token: CancellationToken, args: Args) -> ListenerResult<Self> {
         let server = {
-            let conf = libmq::channel::ChannelConfigurationBuilder::default()
-                .host(&args.mq_host)
-                .port(args.mq_port)
-                .stream_name(&args.mq_stream)
-                .build()
-                .map_err(|e| ListenerError::InvalidMqConfig(e.into()))?;
-            SampleServer::new(SERVICE_NAME.to_string(), &conf)
-                .await
-                .map_err(|e| ListenerError::UnableToConnectToMQ(e.into()))?
+            // Replicas share a consumer name so only one of them answers each call
+            let mut conf = args.mq_url;
+            conf.consumer_name
+                .get_or_insert_with(|| SERVICE_NAME.to_string());
+            conf.single_active_consumer = true;
+            match args.mq_log_dir {
+                Some(dir) => {
+                    let transport = FileTransport::open(dir)
+                        .map_err(|e| ListenerError::UnableToConnectToMQ(e.into()))?;
+                    SampleServer::with_transport(SERVICE_NAME.to_string(), &conf, transport).await
+                }
+                None => SampleServer::new(SERVICE_NAME.to_string(), &conf).await,
+            }
+            .map_err(|e| ListenerError::UnableToConnectToMQ(e.into()))?
         };
 
-        Ok(Self {
+        Ok(Self::with_server(cancellation_token, server))
+    }
+
+    /// Run against a server that's already built, such as one on an in-memory transport
+    pub fn with_server(cancellation_token: CancellationToken, server: SampleServer) -> Self {
+        Self {
             cancellation_token,
             server,
-        })
+        }
     }
 
     pub async fn run(mut self) -> ListenerResult<()> {
         while !self.cancellation_token.is_cancelled() {
-            self.tick().await?;
+            if let Err(e) = self.tick().await {
+                // The server rebuilds its own connections, so only give up once it has
+                if self.server.status() == ConnectionStatus::Failed
+                    || matches!(
+                        e,
+                        ListenerError::ServerError(MessageQueueServerError::Closed)
+                    )
+                {
+                    return Err(e);
+                }
+                tracing::error!("Failed to process calls: {e}");
+            }
         }
 
+        // Make sure a restart picks up after the last call we answered
+        self.server.commit().await?;
+
         Ok(())
     }
 }
 
 impl App {
     async fn tick(&mut self) -> ListenerResult<()> {
-        let deliveries = self.server.recv().await?;
+        let deliveries = tokio::select! {
+            _ = self.cancellation_token.cancelled() => return Ok(()),
+            deliveries = self.server.recv() => deliveries?,
+        };
 
         for delivery in deliveries {
-            match delivery.payload {
+            match delivery.payload.payload {
                 CallPayload::Add { lhs, rhs } => self.process_add(delivery, lhs, rhs).await?,
                 CallPayload::Sub { lhs, rhs } => self.process_sub(delivery, lhs, rhs).await?,
                 CallPayload::Mul { lhs, rhs } => self.process_mul(delivery, lhs, rhs).await?,
                 CallPayload::Div { lhs, rhs } => self.process_div(delivery, lhs, rhs).await?,
+                CallPayload::Unknown(ref unknown) => {
+                    let operation = unknown.tag.clone();
+                    self.process_unsupported(delivery, operation).await?
+                }
             };
         }
 
@@ -61,8 +96,13 @@ impl App {
     }
 
     #[tracing::instrument(skip(self, delivery))]
-    async fn process_add(&mut self, mut delivery: Call, lhs: f32, rhs: f32) -> ListenerResult<()> {
-        delivery.transaction.extract();
+    async fn process_add(
+        &mut self,
+        mut delivery: Delivery<Call>,
+        lhs: f32,
+        rhs: f32,
+    ) -> ListenerResult<()> {
+        delivery.payload.transaction.extract();
 
         tracing::info!(lhs = lhs, rhs = rhs, "Processing add operation");
 
@@ -73,16 +113,21 @@ impl App {
         } else {
             ResponsePayload::Result { result }
         };
-        let response = Response::new(response).with_transaction(delivery.transaction);
-        self.server.send(response).await?;
+        let response = Response::new(response).with_transaction(delivery.payload.transaction);
+        self.server.reply(&delivery.meta, response).await?;
 
         tracing::info!(result = result, "add operation completed successfully");
         Ok(())
     }
 
     #[tracing::instrument(skip(self, delivery))]
-    async fn process_sub(&mut self, mut delivery: Call, lhs: f32, rhs: f32) -> ListenerResult<()> {
-        delivery.transaction.extract();
+    async fn process_sub(
+        &mut self,
+        mut delivery: Delivery<Call>,
+        lhs: f32,
+        rhs: f32,
+    ) -> ListenerResult<()> {
+        delivery.payload.transaction.extract();
 
         tracing::info!(lhs = lhs, rhs = rhs, "Processing sub operation");
 
@@ -93,16 +138,21 @@ impl App {
         } else {
             ResponsePayload::Result { result }
         };
-        let response = Response::new(response).with_transaction(delivery.transaction);
-        self.server.send(response).await?;
+        let response = Response::new(response).with_transaction(delivery.payload.transaction);
+        self.server.reply(&delivery.meta, response).await?;
 
         tracing::info!(result = result, "sub operation completed successfully");
         Ok(())
     }
 
     #[tracing::instrument(skip(self, delivery))]
-    async fn process_mul(&mut self, mut delivery: Call, lhs: f32, rhs: f32) -> ListenerResult<()> {
-        delivery.transaction.extract();
+    async fn process_mul(
+        &mut self,
+        mut delivery: Delivery<Call>,
+        lhs: f32,
+        rhs: f32,
+    ) -> ListenerResult<()> {
+        delivery.payload.transaction.extract();
 
         tracing::info!(lhs = lhs, rhs = rhs, "Processing mul operation");
 
@@ -113,16 +163,21 @@ impl App {
         } else {
             ResponsePayload::Result { result }
         };
-        let response = Response::new(response).with_transaction(delivery.transaction);
-        self.server.send(response).await?;
+        let response = Response::new(response).with_transaction(delivery.payload.transaction);
+        self.server.reply(&delivery.meta, response).await?;
 
         tracing::info!(result = result, "mul operation completed successfully");
         Ok(())
     }
 
     #[tracing::instrument(skip(self, delivery))]
-    async fn process_div(&mut self, mut delivery: Call, lhs: f32, rhs: f32) -> ListenerResult<()> {
-        delivery.transaction.extract();
+    async fn process_div(
+        &mut self,
+        mut delivery: Delivery<Call>,
+        lhs: f32,
+        rhs: f32,
+    ) -> ListenerResult<()> {
+        delivery.payload.transaction.extract();
 
         tracing::info!(lhs = lhs, rhs = rhs, "Processing div operation");
 
@@ -133,10 +188,91 @@ impl App {
         } else {
             ResponsePayload::Result { result }
         };
-        let response = Response::new(response).with_transaction(delivery.transaction);
-        self.server.send(response).await?;
+        let response = Response::new(response).with_transaction(delivery.payload.transaction);
+        self.server.reply(&delivery.meta, response).await?;
 
         tracing::info!(result = result, "div operation completed successfully");
         Ok(())
     }
+
+    /// Calls from a newer client than us get an answer rather than a timeout
+    #[tracing::instrument(skip(self, delivery))]
+    async fn process_unsupported(
+        &mut self,
+        mut delivery: Delivery<Call>,
+        operation: String,
+    ) -> ListenerResult<()> {
+        delivery.payload.transaction.extract();
+
+        tracing::warn!("Unsupported operation");
+
+        let response = Response::new(ResponsePayload::Unsupported { operation })
+            .with_transaction(delivery.payload.transaction);
+        self.server.reply(&delivery.meta, response).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libmq::{
+        channel::ChannelConfiguration, payload::UnknownVariant, transport::InMemoryTransport,
+    };
+    use libshared::mq::SampleClient;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn answers_calls() {
+        let mut conf: ChannelConfiguration = "mq://localhost/sample".parse().unwrap();
+        conf.consumer_name = Some(SERVICE_NAME.to_string());
+        conf.single_active_consumer = true;
+        let transport = InMemoryTransport::new();
+        let server =
+            SampleServer::with_transport(SERVICE_NAME.to_string(), &conf, transport.clone())
+                .await
+                .unwrap();
+        let client = SampleClient::with_transport("client".to_string(), &conf, transport)
+            .await
+            .unwrap();
+
+        let cancellation_token = CancellationToken::new();
+        let app = tokio::spawn(App::with_server(cancellation_token.clone(), server).run());
+
+        let call = |payload| Call {
+            transaction: Default::default(),
+            payload,
+        };
+        let response = client
+            .call(call(CallPayload::Add { lhs: 1.0, rhs: 2.0 }))
+            .await
+            .unwrap();
+        assert!(matches!(response.payload, ResponsePayload::Result { result } if result == 3.0));
+        let response = client
+            .call(call(CallPayload::Mul {
+                lhs: 20.0,
+                rhs: 20.0,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.payload,
+            ResponsePayload::TooBig { lhs, rhs } if lhs == 20.0 && rhs == 20.0
+        ));
+        let response = client
+            .call(call(CallPayload::Unknown(UnknownVariant {
+                tag: "dev.thmsn.sample.call.pow".to_string(),
+                content: Default::default(),
+            })))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.payload,
+            ResponsePayload::Unsupported { operation } if operation == "dev.thmsn.sample.call.pow"
+        ));
+
+        cancellation_token.cancel();
+        assert!(app.await.unwrap().is_ok());
+    }
 }
@@ -1,6 +1,9 @@
+use std::path::PathBuf;
+
 use app::App;
 use clap::Parser;
 use error::ListenerResult;
+use libmq::channel::ChannelConfiguration;
 use tokio_util::sync::CancellationToken;
 
 mod app;
@@ -12,12 +15,12 @@ pub const SERVICE_NAME: &str = "dev.thmsn.sample.listener";
 pub struct Args {
     #[arg(long, env)]
     pub otel_endpoint: String,
+    /// `mq[s]://[user:password@]host[:port][,host[:port]...]/[vhost/]stream[?parameters]`
     #[arg(long, env)]
-    pub mq_host: String,
-    #[arg(long, env)]
-    pub mq_port: u16,
+    pub mq_url: ChannelConfiguration,
+    /// Keep the channel's streams in log files under this directory rather than on the broker
     #[arg(long, env)]
-    pub mq_stream: String,
+    pub mq_log_dir: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 
 use actix_web::{HttpResponseBuilder, Responder, body::BoxBody, http::StatusCode};
+use libmq::client::MessageQueueClientError;
 use serde::Serialize;
 use thiserror::Error;
 
@@ -42,6 +43,9 @@ impl<T: Debug + Serialize> ApiResult<T> {
         };
 
         match error {
+            ApiError::Send(MessageQueueClientError::CallTimeout { .. }) => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
             ApiError::Send(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
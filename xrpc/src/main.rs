@@ -9,9 +9,10 @@ use actix_web::{App, HttpServer, web::Data};
 use clap::Parser;
 use dispatch::configure;
 use liblog::register_tracing_subscriber;
+use libmq::{channel::ChannelConfiguration, transport::FileTransport};
 use libshared::mq::SampleClient;
 use state::AppState;
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 use tracing_actix_web::TracingLogger;
 
 #[derive(Parser, Debug, Clone)]
@@ -20,12 +21,12 @@ struct Args {
     port: u16,
     #[arg(long, env)]
     otel_endpoint: String,
+    /// `mq[s]://[user:password@]host[:port][,host[:port]...]/[vhost/]stream[?parameters]`
     #[arg(long, env)]
-    pub mq_host: String,
+    pub mq_url: ChannelConfiguration,
+    /// Keep the channel's streams in log files under this directory rather than on the broker
     #[arg(long, env)]
-    pub mq_port: u16,
-    #[arg(long, env)]
-    pub mq_stream: String,
+    pub mq_log_dir: Option<PathBuf>,
 }
 
 const SERVICE_NAME: &str = "dev.thmsn.sample.xrpc";
@@ -37,12 +38,14 @@ async fn main() -> anyhow::Result<()> {
 
     let client = {
         let _guard = tracing::info_span!("app.init").entered();
-        let conf = libmq::channel::ChannelConfigurationBuilder::default()
-            .host(&args.mq_host)
-            .port(args.mq_port)
-            .stream_name(&args.mq_stream)
-            .build()?;
-        SampleClient::new(SERVICE_NAME.to_string(), &conf).await?
+        match &args.mq_log_dir {
+            Some(dir) => {
+                let transport = FileTransport::open(dir)?;
+                SampleClient::with_transport(SERVICE_NAME.to_string(), &args.mq_url, transport)
+                    .await?
+            }
+            None => SampleClient::new(SERVICE_NAME.to_string(), &args.mq_url).await?,
+        }
     };
 
     let client = Arc::new(client);
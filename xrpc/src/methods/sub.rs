@@ -1,4 +1,4 @@
-use libshared::mq::{SampleClient, call::Call};
+use libshared::mq::{SampleClient, call::Call, response::ResponsePayload};
 use libtran::Transaction;
 use serde::{Deserialize, Serialize};
 
@@ -15,7 +15,7 @@ pub async fn sub_endpoint(
     transaction: Transaction,
     client: &SampleClient,
     payload: SubEndpointPayload,
-) -> Result<(), ApiError> {
+) -> Result<ResponsePayload, ApiError> {
     let call = Call {
         transaction,
         payload: libshared::mq::call::CallPayload::Sub {
@@ -23,5 +23,9 @@ pub async fn sub_endpoint(
             rhs: payload.rhs,
         },
     };
-    client.send(call).await.map_err(ApiError::Send)
+    client
+        .call(call)
+        .await
+        .map(|response| response.payload)
+        .map_err(ApiError::Send)
 }